@@ -25,6 +25,28 @@ pub struct Config {
     pub strip_prefix: Option<String>,
     pub strip_suffix: Option<String>,
     pub project_regex: Option<String>,
+    pub repo: Option<RepoConfig>,
+    pub publish: Option<PublishConfig>,
+}
+
+/// Settings for the local RPM repository in the target directory
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct RepoConfig {
+    /// Repository name, used for the `.repo` file
+    pub name: Option<String>,
+    /// Base URL the repository is served from, used for the `.repo` file
+    pub baseurl: Option<String>,
+    /// Number of versions to keep for each package
+    pub keep: Option<usize>,
+}
+
+/// Settings for `anda publish`
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct PublishConfig {
+    /// Local directory or rsync destination (`host:path`, `rsync://...`) to publish to
+    pub destination: Option<String>,
+    /// Distribution directory name, e.g. `fc37`. Detected from the dist tag if not set
+    pub distro: Option<String>,
 }
 
 impl Manifest {
@@ -54,9 +76,10 @@ pub struct Project {
     pub rpm: Option<RpmBuild>,
     pub podman: Option<Docker>,
     pub docker: Option<Docker>,
+    pub buildah: Option<Docker>,
     pub flatpak: Option<Flatpak>,
-    pub pre_script: Option<PathBuf>,
-    pub post_script: Option<PathBuf>,
+    pub pre_script: Option<Hook>,
+    pub post_script: Option<Hook>,
     pub env: Option<BTreeMap<String, String>>,
     pub alias: Option<Vec<String>>,
     pub scripts: Option<Vec<PathBuf>>,
@@ -65,13 +88,28 @@ pub struct Project {
     pub update: Option<PathBuf>,
 }
 
+/// A pre/post build hook
+///
+/// Either a path to a script (`.rhai` scripts are run with andax, anything else as a shell script),
+/// or inline shell commands: `pre_script = { commands = ["..."] }`
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
+#[serde(untagged)]
+pub enum Hook {
+    Script(PathBuf),
+    Commands {
+        commands: Vec<String>,
+        /// Directory to run the commands in
+        cwd: Option<PathBuf>,
+    },
+}
+
 #[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
 pub struct RpmBuild {
     pub spec: PathBuf,
     pub sources: Option<PathBuf>,
     pub package: Option<String>,
-    pub pre_script: Option<PathBuf>,
-    pub post_script: Option<PathBuf>,
+    pub pre_script: Option<Hook>,
+    pub post_script: Option<Hook>,
     pub enable_scm: Option<bool>,
     pub scm_opts: Option<BTreeMap<String, String>>,
     pub config: Option<BTreeMap<String, String>>,
@@ -79,11 +117,82 @@ pub struct RpmBuild {
     pub plugin_opts: Option<BTreeMap<String, String>>,
     pub macros: Option<BTreeMap<String, String>>,
     pub opts: Option<BTreeMap<String, String>>,
+    pub rpmlint: Option<Rpmlint>,
+    /// Expected checksums of remote sources, keyed by file name (`sha256:<digest>`)
+    pub checksums: Option<BTreeMap<String, String>>,
+    /// Derive build metadata from the git commit instead of the current date
+    pub reproducible: Option<bool>,
+    pub sign: Option<Sign>,
+    pub mock: Option<Mock>,
+}
+
+/// Mock chroot settings, only used by the mock backend
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+pub struct Mock {
+    /// `nspawn` or `simple`
+    pub isolation: Option<String>,
+    /// Container image to create the bootstrap chroot from
+    pub bootstrap_image: Option<String>,
+    /// Do not clean the chroot before building
+    pub no_clean: Option<bool>,
+    /// Keep the chroot after building
+    pub no_cleanup_after: Option<bool>,
+    /// Enable the root cache plugin, defaults to mock's config
+    pub root_cache: Option<bool>,
+    /// Enable the package manager cache plugin, defaults to mock's config
+    pub package_cache: Option<bool>,
+    /// Unique suffix for the chroot name
+    pub uniqueext: Option<String>,
+}
+
+/// GPG signing for the built RPMs and the RPM repository
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+pub struct Sign {
+    /// GPG key ID to sign with
+    pub key: String,
+    /// GPG home directory, defaults to the user's keyring
+    pub gpg_homedir: Option<PathBuf>,
+}
+
+/// rpmlint checks to run after the RPMs are built
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+pub struct Rpmlint {
+    /// Defaults to `true` if the block is present
+    pub enabled: Option<bool>,
+    pub rpmlintrc: Option<PathBuf>,
+    /// Fail the build on findings of this severity or worse (`info`, `warning` or `error`).
+    /// Defaults to `error`.
+    #[serde(default)]
+    pub threshold: Severity,
+}
+
+/// Severity of a lint finding, ordered from least to most severe
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    #[default]
+    Error,
+}
+
+impl std::fmt::Display for Severity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Severity::Info => write!(f, "info"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Error => write!(f, "error"),
+        }
+    }
 }
 
 #[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
 pub struct Docker {
     pub image: BTreeMap<String, DockerImage>, // tag, file
+    /// Run before each image is built, with the image's tag as the target
+    pub pre_script: Option<Hook>,
+    /// Run after each image is built, with the image's tag as the target
+    pub post_script: Option<Hook>,
 }
 
 /// Turn a string into a BTreeMap<String, String>
@@ -99,17 +208,98 @@ pub fn parse_map(input: &str) -> Option<BTreeMap<String, String>> {
 #[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
 pub struct DockerImage {
     pub dockerfile: Option<String>,
+    /// Image archive to load instead of building from `dockerfile`
     pub import: Option<PathBuf>,
     pub tag_latest: Option<bool>,
+    /// Build context, not needed for imported images
+    #[serde(default)]
     pub context: String,
     pub version: Option<String>,
+    /// Platforms to build for, e.g. `linux/arm64`, combined into a manifest list
+    pub platforms: Option<Vec<String>>,
+    /// Also save the image to `<target_dir>/oci/` as an `oci-archive` or `docker-archive`
+    pub archive: Option<String>,
+    /// Labels to add to the image
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+    /// Extra tag templates, e.g. `${git.short}` or `${branch}-${date}`
+    pub tags: Option<Vec<String>>,
+    /// Build arguments, overridden by `--build-arg` on the command line
+    #[serde(default)]
+    pub build_args: BTreeMap<String, String>,
+    /// Stage to build in a multi-stage Dockerfile
+    pub target: Option<String>,
+    /// Cache sources, passed to `--cache-from` as is
+    pub cache_from: Option<Vec<String>>,
+    /// Cache destinations, passed to `--cache-to` as is
+    pub cache_to: Option<Vec<String>>,
+    /// Build secrets, keyed by id
+    #[serde(default)]
+    pub secret: BTreeMap<String, OciSecret>,
+    /// Network mode for `RUN` instructions, e.g. `none` or `host`
+    pub network: Option<String>,
+    /// Pull policy for base images: `always`, `missing`, `never` or `newer`
+    pub pull: Option<String>,
+    /// Generate an SBOM with syft in this format, e.g. `spdx-json`
+    pub sbom: Option<String>,
+    /// Expose the RPMs in `<target_dir>/rpm` to the build as the `anda-local` dnf repository
+    ///
+    /// Podman and Buildah enable the repository in the build. Docker only gets it as the
+    /// `anda-rpm` build context, which the Dockerfile has to mount and enable itself.
+    pub rpm_repo: Option<bool>,
+}
+
+/// A build secret, read from a file or an environment variable
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+pub struct OciSecret {
+    pub file: Option<PathBuf>,
+    pub env: Option<String>,
 }
 
 #[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone)]
 pub struct Flatpak {
     pub manifest: PathBuf,
-    pub pre_script: Option<PathBuf>,
-    pub post_script: Option<PathBuf>,
+    pub pre_script: Option<Hook>,
+    pub post_script: Option<Hook>,
+    /// Branch to build, if the manifest does not set one
+    pub default_branch: Option<String>,
+    /// Architecture to build for, defaults to the host's
+    pub arch: Option<String>,
+    /// GPG key ID to sign the repository commits and summary with
+    pub gpg_sign: Option<String>,
+    /// GPG home directory, defaults to the user's keyring
+    pub gpg_homedir: Option<PathBuf>,
+    /// URL of a `.flatpakrepo` file for the runtime, embedded in bundles
+    pub runtime_repo: Option<String>,
+    /// Whether to export `manifest` as a `.flatpak` bundle, defaults to `true`
+    pub bundle: Option<bool>,
+    /// File name of the bundle, defaults to `<id>.flatpak`
+    pub bundle_name: Option<String>,
+    /// More manifests to build into the same repository after `manifest`,
+    /// such as extensions or runtimes, keyed by path
+    #[serde(default)]
+    pub extra_manifest: BTreeMap<PathBuf, FlatpakManifest>,
+    pub lint: Option<FlatpakLint>,
+}
+
+/// flatpak-builder-lint and appstreamcli checks to run after the Flatpaks are built
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+pub struct FlatpakLint {
+    /// Defaults to `true` if the block is present
+    pub enabled: Option<bool>,
+    /// Fail the build on findings of this severity or worse (`info`, `warning` or `error`).
+    /// Defaults to `error`.
+    #[serde(default)]
+    pub threshold: Severity,
+}
+
+/// Bundle settings for an extra Flatpak manifest
+#[derive(Deserialize, PartialEq, Eq, Serialize, Debug, Clone, Default)]
+pub struct FlatpakManifest {
+    /// Whether to export a `.flatpak` bundle, defaults to `false`
+    pub bundle: Option<bool>,
+    /// File name of the bundle, defaults to `<id>.flatpak`
+    pub bundle_name: Option<String>,
 }
 
 pub fn to_string(config: Manifest) -> Result<String, hcl::Error> {
@@ -192,13 +382,23 @@ pub fn prefix_config(mut config: Manifest, prefix: &str) -> Manifest {
         } // default!(obj, attr, default_value);
         if let Some(rpm) = &mut new_project.rpm {
             rpm.spec = PathBuf::from(format!("{prefix}/{}", rpm.spec.display()));
-            default!(rpm, pre_script, "rpm_pre.rhai");
-            default!(rpm, post_script, "rpm_post.rhai");
+            prefix_hook(&mut rpm.pre_script, prefix, "rpm_pre.rhai");
+            prefix_hook(&mut rpm.post_script, prefix, "rpm_post.rhai");
             default!(rpm, sources, ".");
+            if let Some(rc) = rpm.rpmlint.as_mut().and_then(|l| l.rpmlintrc.as_mut()) {
+                *rc = PathBuf::from(format!("{prefix}/{}", rc.display()));
+            }
+        }
+        for oci in [&mut new_project.podman, &mut new_project.docker, &mut new_project.buildah]
+            .into_iter()
+            .flatten()
+        {
+            prefix_hook(&mut oci.pre_script, prefix, "oci_pre.rhai");
+            prefix_hook(&mut oci.post_script, prefix, "oci_post.rhai");
         }
         default!(new_project, update, "update.rhai");
-        default!(new_project, pre_script, "pre.rhai");
-        default!(new_project, post_script, "pre.rhai");
+        prefix_hook(&mut new_project.pre_script, prefix, "pre.rhai");
+        prefix_hook(&mut new_project.post_script, prefix, "post.rhai");
 
         if let Some(scripts) = &mut new_project.scripts {
             for scr in scripts {
@@ -213,6 +413,31 @@ pub fn prefix_config(mut config: Manifest, prefix: &str) -> Manifest {
     new_config
 }
 
+/// Prefixes the script path, or the working directory of inline commands, of a hook.
+/// If no hook is set, `default` is used if it exists.
+fn prefix_hook(hook: &mut Option<Hook>, prefix: &str, default: &str) {
+    match hook {
+        Some(Hook::Script(path)) => {
+            if path.as_os_str().is_empty() {
+                *path = default.into();
+            }
+            *path = PathBuf::from(format!("{prefix}/{}", path.display()));
+        }
+        Some(Hook::Commands { cwd, .. }) => {
+            *cwd = Some(match cwd {
+                Some(cwd) => PathBuf::from(format!("{prefix}/{}", cwd.display())),
+                None => PathBuf::from(prefix),
+            });
+        }
+        None => {
+            let p = PathBuf::from(format!("{prefix}/{default}"));
+            if p.exists() {
+                *hook = Some(Hook::Script(p));
+            }
+        }
+    }
+}
+
 pub fn generate_alias(config: &mut Manifest) {
     fn append_vec(vec: &mut Option<Vec<String>>, value: String) {
         if let Some(vec) = vec {
@@ -244,7 +469,7 @@ pub fn generate_alias(config: &mut Manifest) {
 }
 
 pub fn load_from_string(config: &str) -> Result<Manifest, ProjectError> {
-    let mut config: Manifest = hcl::eval::from_str(config, &crate::context::hcl_context())?;
+    let mut config: Manifest = hcl::eval::from_str(config, &crate::context::manifest_context())?;
 
     generate_alias(&mut config);
 
@@ -252,11 +477,55 @@ pub fn load_from_string(config: &str) -> Result<Manifest, ProjectError> {
 }
 
 // Lints and checks the config for errors.
-pub fn check_config(config: Manifest) -> Result<Manifest, ProjectError> {
-    // do nothing for now
+pub fn check_config(mut config: Manifest) -> Result<Manifest, ProjectError> {
+    use crate::context::restore_templates;
+
+    for project in config.project.values_mut() {
+        let images = [&mut project.podman, &mut project.docker, &mut project.buildah]
+            .into_iter()
+            .flatten()
+            .flat_map(|docker| docker.image.values_mut());
+        for image in images {
+            for tag in image.tags.iter_mut().flatten() {
+                *tag = restore_templates(tag);
+            }
+        }
+    }
+
+    // tag template variables are only known at build time, so they cannot be used elsewhere
+    let value =
+        serde_json::to_value(&config).map_err(|e| ProjectError::InvalidManifest(e.to_string()))?;
+    if let Some((path, var)) = find_template_var(&value, "") {
+        return Err(ProjectError::InvalidManifest(format!(
+            "`${{{var}}}` in `{path}` is only available in image tags"
+        )));
+    }
     Ok(config)
 }
 
+/// The path and name of the first tag template variable in `value`
+fn find_template_var(value: &serde_json::Value, path: &str) -> Option<(String, String)> {
+    use crate::context::{find_placeholder, restore_templates};
+    use serde_json::Value;
+
+    match value {
+        Value::String(s) => find_placeholder(s).map(|var| (path.to_string(), var.to_string())),
+        Value::Array(values) => values
+            .iter()
+            .enumerate()
+            .find_map(|(i, v)| find_template_var(v, &format!("{path}[{i}]"))),
+        Value::Object(map) => map.iter().find_map(|(key, v)| {
+            let name = restore_templates(key);
+            let path = if path.is_empty() { name } else { format!("{path}.{name}") };
+            match find_placeholder(key) {
+                Some(var) => Some((path, var.to_string())),
+                None => find_template_var(v, &path),
+            }
+        }),
+        _ => None,
+    }
+}
+
 #[cfg(test)]
 mod test_parser {
     use super::*;
@@ -301,4 +570,160 @@ mod test_parser {
 
         assert_eq!(parse_map("foo=bar,baz=qux"), Some(multi));
     }
+
+    #[test]
+    fn test_hooks() {
+        let config = r#"
+        project "test" {
+            rpm {
+                spec = "umpkg.spec"
+                pre_script = {
+                    commands = ["echo \"test\""]
+                }
+                post_script = "post.sh"
+            }
+            docker {
+                pre_script = "oci.sh"
+                image "test" {}
+            }
+        }
+        "#;
+
+        let config = prefix_config(load_from_string(config).unwrap(), "tests");
+        let rpm = config.project["tests/test"].rpm.as_ref().unwrap();
+        assert_eq!(
+            rpm.pre_script,
+            Some(Hook::Commands {
+                commands: vec!["echo \"test\"".to_string()],
+                cwd: Some("tests".into())
+            })
+        );
+        assert_eq!(rpm.post_script, Some(Hook::Script("tests/post.sh".into())));
+        let docker = config.project["tests/test"].docker.as_ref().unwrap();
+        assert_eq!(docker.pre_script, Some(Hook::Script("tests/oci.sh".into())));
+    }
+
+    #[test]
+    fn test_lint_threshold() {
+        let config = r#"
+        project "test" {
+            rpm {
+                spec = "umpkg.spec"
+                rpmlint {
+                    threshold = "warning"
+                }
+            }
+        }
+        "#;
+
+        let manifest = load_from_string(config).unwrap();
+        let lint = manifest.project["test"].rpm.as_ref().unwrap().rpmlint.as_ref().unwrap();
+        assert_eq!(lint.threshold, Severity::Warning);
+        assert_eq!(Rpmlint::default().threshold, Severity::Error);
+
+        // typos are caught when the manifest is loaded, not after the build
+        assert!(load_from_string(&config.replace("warning", "fatal")).is_err());
+    }
+
+    #[test]
+    fn test_flatpak_manifests() {
+        let config = r#"
+        project "hello" {
+            flatpak {
+                manifest = "org.flatpak.Hello.yml"
+                bundle_name = "hello.flatpak"
+                extra_manifest "org.flatpak.Hello.Plugin.yml" {
+                    bundle = true
+                }
+            }
+        }
+        "#;
+
+        let config = load_from_string(config).unwrap();
+        let flatpak = config.project["hello"].flatpak.as_ref().unwrap();
+        assert_eq!(flatpak.bundle_name.as_deref(), Some("hello.flatpak"));
+        let extra = &flatpak.extra_manifest[&PathBuf::from("org.flatpak.Hello.Plugin.yml")];
+        assert_eq!(extra.bundle, Some(true));
+    }
+
+    #[test]
+    fn test_buildah() {
+        let config = r#"
+        project "base" {
+            buildah {
+                image "ghcr.io/terrapkg/base" {
+                    dockerfile = "Dockerfile"
+                    context = "."
+                }
+            }
+        }
+        "#;
+
+        let config = load_from_string(config).unwrap();
+        let buildah = config.project["base"].buildah.as_ref().unwrap();
+        let image = &buildah.image["ghcr.io/terrapkg/base"];
+        assert_eq!(image.dockerfile.as_deref(), Some("Dockerfile"));
+        assert_eq!(image.context, ".");
+    }
+
+    #[test]
+    fn test_tag_templates() {
+        let config = r#"
+        project "base" {
+            podman {
+                image "ghcr.io/terrapkg/base" {
+                    dockerfile = "Dockerfile"
+                    context = "."
+                    tags = ["${version}", "${git.short}", "${branch}-${date}"]
+                }
+            }
+        }
+        "#;
+
+        let config = load_from_string(config).unwrap();
+        let image = &config.project["base"].podman.as_ref().unwrap().image["ghcr.io/terrapkg/base"];
+        let tags = image.tags.as_ref().unwrap();
+        assert_eq!(tags, &["${version}", "${git.short}", "${branch}-${date}"]);
+
+        #[derive(serde::Serialize)]
+        struct Git {
+            short: &'static str,
+        }
+        #[derive(serde::Serialize)]
+        struct Vars {
+            version: &'static str,
+            branch: &'static str,
+            date: &'static str,
+            git: Git,
+        }
+        let vars = Vars {
+            version: "1.0",
+            branch: "main",
+            date: "20221020",
+            git: Git { short: "b904205c" },
+        };
+        let render = |t: &str| crate::template::render_template(t, &vars).unwrap();
+        assert_eq!(
+            tags.iter().map(|t| render(t)).collect::<Vec<_>>(),
+            ["1.0", "b904205c", "main-20221020"]
+        );
+    }
+
+    #[test]
+    fn test_tag_templates_outside_tags() {
+        let config = r#"
+        project "base" {
+            podman {
+                image "ghcr.io/terrapkg/base" {
+                    dockerfile = "Dockerfile"
+                    version = "${version}"
+                }
+            }
+        }
+        "#;
+
+        let err = load_from_string(config).unwrap_err().to_string();
+        assert!(err
+            .contains("`${version}` in `project.base.podman.image.ghcr.io/terrapkg/base.version`"));
+    }
 }
@@ -39,3 +39,41 @@ pub fn hcl_context() -> Context<'static> {
     });
     c.lock().unwrap().clone()
 }
+
+/// Delimits the placeholders for tag template variables while evaluating a manifest
+const PLACEHOLDER: char = '\u{1}';
+
+/// Generate Context for evaluating manifests
+///
+/// Image tag templates like `${git.short}` can only be rendered at build time,
+/// so their variables evaluate to placeholders here. `check_config()` turns them back
+/// into templates for `render_template()` in image tags, and rejects them anywhere else.
+pub fn manifest_context() -> Context<'static> {
+    let mut ctx = hcl_context();
+    let placeholder = |name: &str| Value::String(format!("{PLACEHOLDER}{name}{PLACEHOLDER}"));
+
+    for var in ["version", "date", "branch"] {
+        ctx.declare_var(var, placeholder(var));
+    }
+    let git = ["commit", "short", "version", "date"]
+        .into_iter()
+        .map(|field| (field.to_string(), placeholder(&format!("git.{field}"))))
+        .collect::<hcl::Map<String, Value>>();
+    ctx.declare_var("git", Value::Object(git));
+
+    ctx
+}
+
+/// The first tag template variable left in `value` by `manifest_context()`
+pub fn find_placeholder(value: &str) -> Option<&str> {
+    value.split(PLACEHOLDER).nth(1)
+}
+
+/// Turns the placeholders from `manifest_context()` in `value` back into templates
+pub fn restore_templates(value: &str) -> String {
+    value
+        .split(PLACEHOLDER)
+        .enumerate()
+        .map(|(i, part)| if i % 2 == 1 { format!("${{{part}}}") } else { part.to_string() })
+        .collect()
+}
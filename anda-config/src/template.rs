@@ -1,6 +1,7 @@
 use crate::context::hcl_context;
 use hcl::eval::Evaluate;
 use hcl::template::Template;
+use serde::Serialize;
 use std::str::FromStr;
 
 pub fn parse_template(template: &str) -> Result<String, String> {
@@ -10,6 +11,18 @@ pub fn parse_template(template: &str) -> Result<String, String> {
     Ok(value)
 }
 
+/// Renders `template` with the fields of `vars` declared as variables
+pub fn render_template<T: Serialize>(template: &str, vars: &T) -> Result<String, String> {
+    let template = Template::from_str(template).map_err(|e| e.to_string())?;
+    let mut ctx = hcl_context();
+    if let hcl::Value::Object(vars) = hcl::to_value(vars).map_err(|e| e.to_string())? {
+        for (key, value) in vars {
+            ctx.declare_var(key, value);
+        }
+    }
+    template.evaluate(&ctx).map_err(|e| e.to_string())
+}
+
 #[test]
 fn test_templ() {
     let template = "hello ${env.USER}";
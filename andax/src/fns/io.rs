@@ -29,7 +29,7 @@ macro_rules! _cmd {
 type T = Result<(i32, String, String), Box<EvalAltResult>>;
 
 /// for andax, shell():
-/// ```
+/// ```rhai
 /// sh("echo hai");
 /// sh(["echo", "hai"]);
 /// sh(["rm", "-rf", "/path/with/some space"]);
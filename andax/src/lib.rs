@@ -3,6 +3,6 @@ mod fns;
 mod run;
 
 pub use fns::rpm::RPMSpec;
-pub use rhai::Map;
+pub use rhai::{Map, Scope};
 pub use run::errhdl;
 pub use run::run;
@@ -243,23 +243,21 @@ fn hint_ear(sl: &str, lns: &str, ear: &EvalAltResult, _rhai_fn: &str) -> Option<
     }
     use EvalAltResult::*;
     match ear {
-        ErrorRuntime(d, _) => {
-            if d.is_string() {
-                let s = d.clone().into_string().expect("sting.");
-                if s == "env(`GITHUB_TOKEN`) not present" {
-                    h!(
-                        r#"gh() requires the environment variable `GITHUB_TOKEN` to be set as a Github token so as to avoid rate-limits:
-                        https://docs.github.com/en/rest/overview/resources-in-the-rest-api#rate-limiting
-                        To create a Github token, see:
-                        https://docs.github.com/en/authentication/keeping-your-account-and-data-secure/creating-a-personal-access-token"#
-                    );
-                }
+        ErrorRuntime(d, _) if d.is_string() => {
+            let s = d.clone().into_string().expect("sting.");
+            if s == "env(`GITHUB_TOKEN`) not present" {
+                h!(
+                    r#"gh() requires the environment variable `GITHUB_TOKEN` to be set as a Github token so as to avoid rate-limits:
+                    https://docs.github.com/en/rest/overview/resources-in-the-rest-api#rate-limiting
+                    To create a Github token, see:
+                    https://docs.github.com/en/authentication/keeping-your-account-and-data-secure/creating-a-personal-access-token"#
+                );
             }
         }
-        ErrorMismatchOutputType(req, actual, _) => {
-            if sl.contains("json(") && req == "map" && actual == "array" {
-                h!("If the json root is an array `[]`, use json_arr() instead.");
-            }
+        ErrorMismatchOutputType(req, actual, _)
+            if sl.contains("json(") && req == "map" && actual == "array" =>
+        {
+            h!("If the json root is an array `[]`, use json_arr() instead.");
         }
         _ => {}
     }
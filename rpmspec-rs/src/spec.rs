@@ -1,4 +1,7 @@
-use chrono::{DateTime, FixedOffset, NaiveDate};
+// The spec parser is still a work in progress, and not used by anda yet
+#![allow(dead_code)]
+
+use chrono::NaiveDate;
 use pest::{iterators::Pair, Parser};
 use pest_derive::Parser;
 use std::{
@@ -6,8 +9,6 @@ use std::{
     fmt::{Display, Formatter},
 };
 
-use serde::{Deserializer, Serializer};
-
 // Special macros that need not be expanded
 const SPECIAL_MACROS: &[&str] = &[
     "prep",
@@ -185,7 +186,7 @@ impl Display for Changelog {
             for line in &entry.text {
                 output.push_str(&format!("- {}\n", line));
             }
-            output.push_str("\n");
+            output.push('\n');
         }
         write!(f, "{}", output)
     }
@@ -214,7 +215,7 @@ fn test_sadas() {
     println!("{}", changelog);
 
     let specfile = include_str!("../../tests/umpkg.spec");
-    let spec = SpecParser::parse(Rule::file, specfile).unwrap();
+    let _spec = SpecParser::parse(Rule::file, specfile).unwrap();
     // println!("{:?}", spec);
     // let spec = Body::parse(specfile);
 }
@@ -1,17 +1,86 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, path::Path};
 
-use crate::cli::PackageType;
+use color_eyre::{eyre::eyre, Result};
+use serde::{Deserialize, Serialize};
 
-#[derive(Clone)]
+use crate::{cli::PackageType, lint::Finding, sources::sha256sum};
+
+/// The build report for a project
+///
+/// This is printed after the build, and saved as JSON in `<target_dir>/reports/<project>.json`.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
 pub struct Artifacts {
     pub packages: BTreeMap<String, PackageType>,
+    /// Findings from post-build lint checks
+    #[serde(default)]
+    pub lints: Vec<Finding>,
+    /// Pushed OCI images, pinned to the digest that was pushed
+    #[serde(default)]
+    pub digests: BTreeMap<String, String>,
+    /// Other files produced by the build, e.g. image archives, with their SHA-256 checksums
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+    /// SBOMs of OCI images, keyed by image
+    #[serde(default)]
+    pub sboms: BTreeMap<String, String>,
 }
 
 impl Artifacts {
     pub fn new() -> Self {
-        Artifacts { packages: BTreeMap::new() }
+        Artifacts {
+            packages: BTreeMap::new(),
+            lints: Vec::new(),
+            digests: BTreeMap::new(),
+            files: BTreeMap::new(),
+            sboms: BTreeMap::new(),
+        }
     }
     pub fn add(&mut self, name: String, package_type: PackageType) {
         self.packages.insert(name, package_type);
     }
+
+    pub fn add_lints<I: IntoIterator<Item = Finding>>(&mut self, findings: I) {
+        self.lints.extend(findings);
+    }
+
+    pub fn add_digest(&mut self, image: String, pinned: String) {
+        self.digests.insert(image, pinned);
+    }
+
+    /// Records `path`, along with its checksum
+    pub fn add_file(&mut self, path: &Path) -> Result<()> {
+        let checksum = sha256sum(path)?;
+        self.files.insert(path.display().to_string(), format!("sha256:{checksum}"));
+        Ok(())
+    }
+
+    /// Records the SBOM of `image`, along with its checksum
+    pub fn add_sbom(&mut self, image: String, path: &Path) -> Result<()> {
+        self.add_file(path)?;
+        self.sboms.insert(image, path.display().to_string());
+        Ok(())
+    }
+
+    /// Path of the build report for `project`
+    pub fn report_path(target_dir: &Path, project: &str) -> std::path::PathBuf {
+        target_dir.join("reports").join(format!("{project}.json"))
+    }
+
+    /// Writes the build report for `project` into `target_dir`
+    pub fn save(&self, target_dir: &Path, project: &str) -> Result<()> {
+        let path = Self::report_path(target_dir, project);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        std::fs::write(path, serde_json::to_string_pretty(self)?)?;
+        Ok(())
+    }
+
+    /// Reads the build report for `project` from `target_dir`
+    pub fn load(target_dir: &Path, project: &str) -> Result<Self> {
+        let path = Self::report_path(target_dir, project);
+        let report = std::fs::read_to_string(&path)
+            .map_err(|e| eyre!("Cannot read build report {}: {e}", path.display()))?;
+        Ok(serde_json::from_str(&report)?)
+    }
 }
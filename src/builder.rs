@@ -2,52 +2,92 @@ use crate::{
     artifacts::Artifacts,
     cli::{Cli, FlatpakOpts, OciOpts, PackageType, RpmOpts},
     flatpak::{FlatpakArtifact, FlatpakBuilder},
-    oci::{build_oci, OCIBackend},
-    rpm_spec::{RPMBuilder, RPMExtraOptions, RPMOptions},
+    hooks::{run_hook, HookContext},
+    lint::{appstream_validate, check_threshold, flatpak_builder_lint, rpmlint, Finding},
+    oci::{build_oci, export, push_artifacts, sbom, GitVars, OCIBackend, TagVars},
+    repo::RpmRepo,
+    rpm_spec::{spec_version, MockBackend, MockOpts, RPMBuilder, RPMExtraOptions, RPMOptions},
+    sign::SignOpts,
+    sources::fetch_sources,
     update::run_scripts,
-    util::{get_commit_id_cwd, get_date},
+    util::{get_branch_cwd, AutoGit},
 };
-use anda_config::{Docker, Flatpak, Project, RpmBuild};
-use cmd_lib::run_cmd;
+use anda_config::{Docker, Flatpak, Project, RpmBuild, Rpmlint};
 use color_eyre::{eyre::eyre, eyre::Context, Result};
-use std::{
-    path::{Path, PathBuf},
-    process::Command,
-};
-use tracing::{debug, error, info, trace};
+use std::path::{Path, PathBuf};
+use tracing::{debug, info, trace};
 
+/// Builds the RPMs of `spec` and adds them to `repo`, returning them with their lint findings
+///
+/// RPMs that fail the lint threshold are not added to the repository.
 pub async fn build_rpm(
     opts: &mut RPMOptions,
     spec: &Path,
     builder: RPMBuilder,
-    output_dir: &Path,
+    repo: &RpmRepo,
     rpmb_opts: &RpmOpts,
-) -> Result<Vec<PathBuf>> {
-    let repo_path = output_dir.join("rpm");
-    println!("Building RPMs in {}", repo_path.display());
-    let repodata_path = repo_path.join("repodata");
+    lint: Option<&Rpmlint>,
+) -> Result<(Vec<PathBuf>, Vec<Finding>)> {
+    println!("Building RPMs in {}", repo.root().display());
 
-    if repodata_path.exists() {
-        let repo_path = repo_path.canonicalize()?;
+    prepare_rpm(opts, spec, repo, rpmb_opts).await?;
 
-        let repo_path = format!("file://{}", repo_path.display());
-        if opts.extra_repos.is_none() {
-            opts.extra_repos = Some(vec![repo_path]);
-        } else {
-            opts.extra_repos.as_mut().unwrap().push(repo_path);
+    trace!("Building RPMs with {opts:?}");
+
+    let rpms = builder.build(spec, opts).await?;
+
+    let mut findings = Vec::new();
+    if let Some(lint) = lint.filter(|l| l.enabled.unwrap_or(true)) {
+        info!("Running rpmlint");
+        findings = rpmlint(&rpms, lint.rpmlintrc.as_deref()).await?;
+        for finding in &findings {
+            info!("{finding}");
         }
+        check_threshold(&findings, lint.threshold)?;
+    }
+
+    if let Some(sign) = &opts.sign {
+        sign.sign_rpms(&rpms).await?;
+    }
+
+    // only touch the repository once the build has succeeded
+    let rpms = repo.add(rpms)?;
+    repo.update(false, opts.sign.as_ref()).await?;
+
+    Ok((rpms, findings))
+}
+
+/// Adds the local and extra repositories, defines the macros and fetches the sources
+async fn prepare_rpm(
+    opts: &mut RPMOptions,
+    spec: &Path,
+    repo: &RpmRepo,
+    rpmb_opts: &RpmOpts,
+) -> Result<()> {
+    if repo.exists() {
+        let repo_path = repo.root().canonicalize()?;
+
+        opts.add_extra_repo(format!("file://{}", repo_path.display()));
     } else {
         debug!("No repodata found, skipping");
     }
 
     for repo in &rpmb_opts.extra_repos {
-        if opts.extra_repos.is_none() {
-            opts.extra_repos = Some(vec![repo.clone()]);
-        } else {
-            opts.extra_repos.as_mut().unwrap().push(repo.clone());
-        }
+        opts.add_extra_repo(repo.clone());
+    }
+
+    define_macros(opts, rpmb_opts)?;
+
+    if !opts.scm_enable {
+        fetch_sources(spec, opts).await?;
+        opts.sources = opts.staging_dir.clone();
     }
 
+    Ok(())
+}
+
+/// Defines the macros from the command line, along with anda's own `autogit*` macros
+pub fn define_macros(opts: &mut RPMOptions, rpmb_opts: &RpmOpts) -> Result<()> {
     for rpmmacro in &rpmb_opts.rpm_macro {
         let split = rpmmacro.split_once(' ');
         if let Some((key, value)) = split {
@@ -58,52 +98,38 @@ pub async fn build_rpm(
     }
     {
         // HACK: Define macro for autogitversion
-        // get git version
-        let commit_id = get_commit_id_cwd();
-
-        let date = get_date();
-        let mut tmp = String::new();
-
-        let autogitversion = commit_id
-            .as_ref()
-            .map(|commit| {
-                tmp = format!("{date}.{}", commit.chars().take(8).collect::<String>());
-                &tmp
-            })
-            .unwrap_or(&date);
+        let autogit = AutoGit::new(opts.reproducible)?;
 
-        // limit to 16 chars
+        opts.def_macro("autogitversion", &autogit.version);
 
-        opts.def_macro("autogitversion", autogitversion);
+        opts.def_macro("autogitcommit", autogit.commit.as_deref().unwrap_or("unknown"));
 
-        opts.def_macro("autogitcommit", &commit_id.unwrap_or_else(|| "unknown".into()));
+        opts.def_macro("autogitdate", &autogit.date);
 
-        opts.def_macro("autogitdate", &date);
+        if let Some(epoch) = autogit.commit_time {
+            opts.env.insert("SOURCE_DATE_EPOCH".to_string(), epoch.to_string());
+            opts.def_macro("use_source_date_epoch_as_buildtime", "1");
+            opts.def_macro("clamp_mtime_to_source_date_epoch", "1");
+        }
     }
 
-    trace!("Building RPMs with {opts:?}");
-
-    let builder = builder.build(spec, opts).await;
-
-    run_cmd!(createrepo_c --quiet --update ${repo_path})?;
-
-    builder
+    Ok(())
 }
 
-pub async fn build_flatpak(
+/// Sets up a Flatpak builder with its output in `output_dir`
+pub fn flatpak_builder(
     output_dir: &Path,
-    manifest: &Path,
+    flatpak: &Flatpak,
     flatpak_opts: &mut FlatpakOpts,
-) -> Result<Vec<FlatpakArtifact>> {
-    let mut artifacts = Vec::new();
-
+) -> FlatpakBuilder {
     let out = output_dir.join("flatpak");
 
     let flat_out = out.join("build");
+    let flat_state = flatpak_opts.flatpak_state_dir.clone().unwrap_or_else(|| out.join("state"));
     let flat_repo = out.join("repo");
     let flat_bundles = out.join("bundles");
 
-    let mut builder = FlatpakBuilder::new(flat_out, flat_repo, flat_bundles);
+    let mut builder = FlatpakBuilder::new(flat_out, flat_state, flat_repo, flat_bundles);
 
     for extra_source in flatpak_opts.flatpak_extra_sources.iter_mut() {
         builder.add_extra_source(PathBuf::from(std::mem::take(extra_source)));
@@ -117,32 +143,69 @@ pub async fn build_flatpak(
         builder.add_extra_args("--delete-build-dirs".to_string());
     }
 
-    let flatpak = builder.build(manifest).await?;
-    artifacts.push(FlatpakArtifact::Ref(flatpak.clone()));
-    artifacts.push(FlatpakArtifact::Bundle(builder.bundle(&flatpak).await?));
+    if let Some(branch) = &flatpak.default_branch {
+        builder.default_branch(branch.clone());
+    }
+    if let Some(arch) = &flatpak.arch {
+        builder.arch(arch.clone());
+    }
+    if let Some(key) = &flatpak.gpg_sign {
+        builder.gpg_sign(SignOpts::new(key.clone(), flatpak.gpg_homedir.clone()));
+    }
+    if let Some(url) = &flatpak.runtime_repo {
+        builder.runtime_repo(url.clone());
+    }
+    builder.offline(flatpak_opts.flatpak_offline);
+
+    builder
+}
+
+pub async fn build_flatpak(
+    output_dir: &Path,
+    flatpak: &Flatpak,
+    flatpak_opts: &mut FlatpakOpts,
+) -> Result<Vec<FlatpakArtifact>> {
+    let mut artifacts = Vec::new();
+    let builder = flatpak_builder(output_dir, flatpak, flatpak_opts);
+
+    // the main manifest is bundled by default, extra ones only on request
+    let manifests =
+        std::iter::once((&flatpak.manifest, flatpak.bundle.unwrap_or(true), &flatpak.bundle_name))
+            .chain(flatpak.extra_manifest.iter().map(|(manifest, opts)| {
+                (manifest, opts.bundle.unwrap_or(false), &opts.bundle_name)
+            }));
+
+    let mut built = Vec::new();
+    for (manifest, bundle, bundle_name) in manifests {
+        built.push((builder.build(manifest).await?, bundle, bundle_name));
+    }
+    builder.update_repo().await?;
+
+    for (built, bundle, bundle_name) in built {
+        let path =
+            if bundle { Some(builder.bundle(&built, bundle_name.as_deref()).await?) } else { None };
+        artifacts.push(built);
+        artifacts.extend(path.map(FlatpakArtifact::Bundle));
+    }
 
     Ok(artifacts)
 }
 
-macro_rules! script {
-    ($name:expr, $scr:expr, $( $var:ident ),*) => {
-        let sc = andax::run(
-            $name,
-            &$scr,
-            std::collections::BTreeMap::new(),
-            |_sc| {
-                $( _sc.push(stringify!($var), $var); )*
-            },
-        );
-        #[allow(unused_assignments)]
-        if let Some(_sc) = sc {
-            $( $var = _sc.get_value(stringify!($var)).expect(concat!("No `{}` in scope", stringify!($var))); )*
-        } else {
-            error!(
-                scr = $scr.display().to_string(),
-                concat!(stringify!($scr), " —— failed with aforementioned exception.")
-            );
-            return Err(eyre!(concat!(stringify!($scr), " failed")));
+/// Runs a hook, pushing the given variables into the scope of rhai scripts
+/// and reading them back afterwards
+macro_rules! hook {
+    ($target:expr, $hook:expr, $ctx:expr, $( $var:ident ),*) => {
+        #[allow(unused_variables, unused_assignments)]
+        if let Some(sc) = run_hook($target, $hook, $ctx, |_sc| {
+            $( _sc.push(stringify!($var), $var.clone()); )*
+        })
+        .await?
+        {
+            $(
+                $var = sc
+                    .get_value(stringify!($var))
+                    .ok_or_else(|| eyre!(concat!("Hook removed `", stringify!($var), "` from its scope")))?;
+            )*
         }
     };
 }
@@ -151,51 +214,33 @@ macro_rules! script {
 // yeah this is ugly and relies on side effects, but it reduces code duplication
 // to anyone working on this, please rewrite this call to make it more readable
 pub async fn build_rpm_call(
-    cli: &Cli,
     mut opts: RPMOptions,
     rpmbuild: &RpmBuild,
     mut rpm_builder: RPMBuilder,
     artifact_store: &mut Artifacts,
     rpmb_opts: &RpmOpts,
+    repo: &RpmRepo,
+    hook_ctx: &HookContext,
 ) -> Result<()> {
+    let target = rpmbuild.spec.to_string_lossy();
+
     // run pre-build script
     if let Some(pre_script) = &rpmbuild.pre_script {
-        if pre_script.extension().unwrap_or_default() == ".rhai" {
-            script!(
-                rpmbuild.spec.as_os_str().to_str().unwrap_or(""),
-                pre_script,
-                opts,
-                rpm_builder
-            );
-        } else {
-            let p = Command::new("sh").arg("-c").arg(pre_script).status()?;
-            if !p.success() {
-                return Err(eyre!(p));
-            }
-        }
+        hook!(&target, pre_script, hook_ctx, opts, rpm_builder);
     }
 
-    let art = build_rpm(&mut opts, &rpmbuild.spec, rpm_builder, &cli.target_dir, rpmb_opts).await?;
+    let lint = rpmbuild.rpmlint.as_ref();
+    let (art, findings) =
+        build_rpm(&mut opts, &rpmbuild.spec, rpm_builder, repo, rpmb_opts, lint).await?;
 
-    // `opts` is consumed in build_rpm()/build()
-    if let Some(post_script) = &rpmbuild.post_script {
-        if post_script.extension().unwrap_or_default() == ".rhai" {
-            script!(
-                rpmbuild.spec.as_os_str().to_str().unwrap_or(""),
-                post_script,
-                opts,
-                rpm_builder
-            );
-        } else {
-            let p = Command::new("sh").arg("-c").arg(post_script).status()?;
-            if !p.success() {
-                return Err(eyre!(p));
-            }
-        }
+    for artifact in &art {
+        artifact_store.add(artifact.to_string_lossy().to_string(), PackageType::Rpm);
     }
+    artifact_store.add_lints(findings);
 
-    for artifact in art {
-        artifact_store.add(artifact.to_string_lossy().to_string(), PackageType::Rpm);
+    // `opts` is consumed in build_rpm()/build()
+    if let Some(post_script) = &rpmbuild.post_script {
+        hook!(&target, post_script, hook_ctx, opts, rpm_builder);
     }
 
     Ok(())
@@ -206,67 +251,145 @@ pub async fn build_flatpak_call(
     flatpak: &Flatpak,
     artifact_store: &mut Artifacts,
     mut flatpak_opts: FlatpakOpts,
+    hook_ctx: &HookContext,
 ) -> Result<()> {
+    let target = flatpak.manifest.to_string_lossy();
+
     if let Some(pre_script) = &flatpak.pre_script {
-        script!(
-            flatpak.manifest.as_path().to_str().unwrap_or("<flatpak>"),
-            pre_script,
-            flatpak_opts
-        );
+        hook!(&target, pre_script, hook_ctx, flatpak_opts);
     }
 
-    let art = build_flatpak(&cli.target_dir, &flatpak.manifest, &mut flatpak_opts).await.unwrap();
+    let art = build_flatpak(&cli.target_dir, flatpak, &mut flatpak_opts.clone()).await?;
 
-    for artifact in art {
+    for artifact in &art {
         artifact_store.add(artifact.to_string(), PackageType::Flatpak);
     }
 
+    if let Some(lint) = flatpak.lint.as_ref().filter(|l| l.enabled.unwrap_or(true)) {
+        info!("Linting Flatpaks");
+        let builder = flatpak_builder(&cli.target_dir, flatpak, &mut flatpak_opts);
+
+        let mut findings = Vec::new();
+        for manifest in std::iter::once(&flatpak.manifest).chain(flatpak.extra_manifest.keys()) {
+            findings.extend(flatpak_builder_lint("manifest", manifest).await?);
+        }
+        findings.extend(flatpak_builder_lint("repo", builder.repo()).await?);
+        for id in art.iter().filter_map(|a| a.id()) {
+            for metainfo in builder.metainfo(id) {
+                findings.extend(appstream_validate(&metainfo).await?);
+            }
+        }
+
+        for finding in &findings {
+            info!("{finding}");
+        }
+        artifact_store.add_lints(findings.clone());
+        check_threshold(&findings, lint.threshold)?;
+    }
+
     if let Some(post_script) = &flatpak.post_script {
-        script!(flatpak.manifest.as_path().to_str().unwrap_or("<flatpak>"), post_script,);
+        hook!(&target, post_script, hook_ctx,);
     }
 
     Ok(())
 }
 
-pub fn build_oci_call(
+#[allow(clippy::too_many_arguments)]
+pub async fn build_oci_call(
     backend: OCIBackend,
-    _cli: &Cli,
+    cli: &Cli,
     manifest: &mut Docker,
     artifact_store: &mut Artifacts,
+    tag_vars: Option<&TagVars>,
+    oci_opts: &OciOpts,
+    repo: &RpmRepo,
+    hook_ctx: &HookContext,
 ) -> Result<()> {
     let art_type = match backend {
         OCIBackend::Docker => PackageType::Docker,
         OCIBackend::Podman => PackageType::Podman,
+        OCIBackend::Buildah => PackageType::Buildah,
     };
 
     for (tag, image) in std::mem::take(&mut manifest.image).into_iter() {
-        let art = build_oci(
-            backend,
-            image.dockerfile.unwrap(),
-            image.tag_latest.unwrap_or(false),
-            tag,
-            image.version.unwrap_or_else(|| "latest".to_string()),
-            image.context,
-        );
+        if let Some(pre_script) = &manifest.pre_script {
+            hook!(&tag, pre_script, hook_ctx,);
+        }
+
+        let archive = image.archive.as_deref().map(str::parse).transpose()?;
+        let sbom_format = image.sbom.clone();
+        let target = tag.clone();
+        let art = build_oci(backend, tag, image, tag_vars, oci_opts, repo.root()).await?;
+
+        // the first tag is `tag:version`, or the manifest list for multi-platform builds
+        if let Some(format) = archive {
+            let path = export(backend, &art[0], format, &cli.target_dir.join("oci")).await?;
+            artifact_store.add_file(&path)?;
+        }
+
+        if let Some(format) = sbom_format {
+            let path = sbom(backend, &art[0], &format, &cli.target_dir.join("oci")).await?;
+            artifact_store.add_sbom(art[0].clone(), &path)?;
+        }
 
         for artifact in art {
             artifact_store.add(artifact.to_string(), art_type);
         }
+
+        if let Some(post_script) = &manifest.post_script {
+            hook!(&target, post_script, hook_ctx,);
+        }
     }
 
     Ok(())
 }
 
-// project parser
+/// Variables for the tag templates of the images in `manifest`, if any of them use them
+///
+/// The version and `git` variables are derived like they are for the RPM build,
+/// with the same macros and reproducibility settings.
+async fn oci_tag_vars(
+    manifest: &Docker,
+    rpmbuild: Option<&RpmBuild>,
+    rpm_opts: &RPMOptions,
+    rpmb_opts: &RpmOpts,
+) -> Result<Option<TagVars>> {
+    if !manifest.image.values().any(|image| image.tags.is_some()) {
+        return Ok(None);
+    }
 
-pub async fn build_project(
+    let mut opts = rpm_opts.clone();
+    // `rpm_options()` only sets this for projects with an RPM build
+    opts.reproducible |= rpmb_opts.reproducible;
+    define_macros(&mut opts, rpmb_opts)?;
+
+    let version = match rpmbuild {
+        Some(rpm) => spec_version(&rpm.spec, &opts.macros).await?,
+        None => "latest".to_string(),
+    };
+    let autogit = |name: &str| opts.macros.get(name).cloned().unwrap_or_default();
+    let commit = autogit("autogitcommit");
+
+    Ok(Some(TagVars {
+        version,
+        date: autogit("autogitdate"),
+        branch: get_branch_cwd().unwrap_or_else(|| "detached".to_string()),
+        git: GitVars {
+            short: commit.chars().take(8).collect(),
+            commit,
+            version: autogit("autogitversion"),
+            date: autogit("autogitdate"),
+        },
+    }))
+}
+
+/// Computes the RPM build options for a project from its manifest and the command line
+pub fn rpm_options(
     cli: &Cli,
-    project: Project,
-    package: PackageType,
+    name: &str,
+    project: &Project,
     rpmb_opts: &RpmOpts,
-    flatpak_opts: &FlatpakOpts,
-    _oci_opts: &OciOpts,
-) -> Result<()> {
+) -> Result<RPMOptions> {
     let cwd = std::env::current_dir().unwrap();
 
     let mut rpm_opts = RPMOptions::new(rpmb_opts.mock_config.clone(), cwd, cli.target_dir.clone());
@@ -276,7 +399,24 @@ pub async fn build_project(
             rpm_opts.sources = srcdir.to_path_buf();
         }
         rpm_opts.no_mirror = rpmb_opts.no_mirrors;
-        rpm_opts.def_macro("_disable_source_fetch", "0");
+        rpm_opts.log_dir = Some(cli.target_dir.join("logs").join(name));
+        rpm_opts.staging_dir = cli.target_dir.join("sources").join(name);
+        rpm_opts.offline = rpmb_opts.rpm_offline;
+        if let Some(checksums) = &rpmbuild.checksums {
+            rpm_opts.source_checksums = checksums.clone();
+        }
+        rpm_opts.reproducible = rpmb_opts.reproducible || rpmbuild.reproducible.unwrap_or(false);
+
+        // signing options from the command line take precedence over the manifest
+        let sign_key =
+            rpmb_opts.sign_key.clone().or_else(|| rpmbuild.sign.as_ref().map(|s| s.key.clone()));
+        if let Some(key) = sign_key {
+            let homedir = rpmb_opts
+                .gpg_homedir
+                .clone()
+                .or_else(|| rpmbuild.sign.as_ref().and_then(|s| s.gpg_homedir.clone()));
+            rpm_opts.sign = Some(SignOpts::new(key, homedir));
+        }
         rpm_opts.config_opts.push("external_buildrequires=True".to_string());
 
         // Enable SCM sources
@@ -284,6 +424,10 @@ pub async fn build_project(
             rpm_opts.scm_enable = bool;
         }
 
+        // sources are staged by `fetch_sources()`, unless mock's SCM plugin gets them
+        let fetch = rpm_opts.scm_enable && !rpm_opts.offline;
+        rpm_opts.def_macro("_disable_source_fetch", if fetch { "0" } else { "1" });
+
         // load SCM options
         if let Some(scm_opt) = &rpmbuild.scm_opts {
             rpm_opts.scm_opts =
@@ -305,6 +449,8 @@ pub async fn build_project(
                 plugin_opt.iter().map(|(k, v)| format!("{k}={v}")).collect::<Vec<String>>();
         }
 
+        rpm_opts.mock = mock_options(rpmbuild.mock.as_ref(), rpmb_opts)?;
+
         if rpmb_opts.mock_config.is_none() {
             if let Some(mockcfg) = &rpmbuild.mock_config {
                 rpm_opts.mock_config = Some(mockcfg.to_string());
@@ -312,38 +458,126 @@ pub async fn build_project(
             // TODO: Implement global settings
         }
     }
+    Ok(rpm_opts)
+}
+
+/// Merges the mock options from the manifest and the command line,
+/// with the command line taking precedence
+pub fn mock_options(mock: Option<&anda_config::Mock>, rpmb_opts: &RpmOpts) -> Result<MockOpts> {
+    let mock = mock.cloned().unwrap_or_default();
+    let isolation = match (rpmb_opts.mock_isolation, &mock.isolation) {
+        (Some(isolation), _) => Some(isolation),
+        (None, Some(isolation)) => Some(isolation.parse().map_err(|e: String| eyre!(e))?),
+        (None, None) => None,
+    };
+    Ok(MockOpts {
+        isolation,
+        bootstrap_image: rpmb_opts.mock_bootstrap_image.clone().or(mock.bootstrap_image),
+        no_clean: rpmb_opts.mock_no_clean || mock.no_clean.unwrap_or(false),
+        no_cleanup_after: rpmb_opts.mock_no_cleanup_after || mock.no_cleanup_after.unwrap_or(false),
+        root_cache: if rpmb_opts.mock_no_root_cache { Some(false) } else { mock.root_cache },
+        package_cache: if rpmb_opts.mock_no_package_cache {
+            Some(false)
+        } else {
+            mock.package_cache
+        },
+        uniqueext: rpmb_opts.mock_uniqueext.clone().or(mock.uniqueext),
+    })
+}
+
+// project parser
+
+#[allow(clippy::too_many_arguments)]
+pub async fn build_project(
+    cli: &Cli,
+    name: &str,
+    project: Project,
+    package: PackageType,
+    repo: &RpmRepo,
+    rpmb_opts: &RpmOpts,
+    flatpak_opts: &FlatpakOpts,
+    oci_opts: &OciOpts,
+) -> Result<()> {
+    let rpm_opts = rpm_options(cli, name, &project, rpmb_opts)?;
+    let hook_ctx = HookContext::new(name, &project);
     let mut artifacts = Artifacts::new();
 
+    if let Some(pre_script) = &project.pre_script {
+        hook!(name, pre_script, &hook_ctx,);
+    }
+
     // get project
     match package {
         PackageType::All => {
-            // build all packages
+            // build all packages, RPMs first so images can install them with `rpm_repo`
             if let Some(rpmbuild) = &project.rpm {
                 build_rpm_call(
-                    cli,
-                    rpm_opts,
+                    rpm_opts.clone(),
                     rpmbuild,
                     rpmb_opts.rpm_builder.into(),
                     &mut artifacts,
                     rpmb_opts,
+                    repo,
+                    &hook_ctx,
                 )
                 .await
                 .with_context(|| "Failed to build RPMs".to_string())?;
             }
             if let Some(flatpak) = &project.flatpak {
-                build_flatpak_call(cli, flatpak, &mut artifacts, flatpak_opts.clone())
+                build_flatpak_call(cli, flatpak, &mut artifacts, flatpak_opts.clone(), &hook_ctx)
                     .await
                     .with_context(|| "Failed to build Flatpaks".to_string())?;
             }
 
             if let Some(mut podman) = project.podman {
-                build_oci_call(OCIBackend::Podman, cli, &mut podman, &mut artifacts)
-                    .with_context(|| "Failed to build Podman images".to_string())?;
+                let tag_vars =
+                    oci_tag_vars(&podman, project.rpm.as_ref(), &rpm_opts, rpmb_opts).await?;
+                build_oci_call(
+                    OCIBackend::Podman,
+                    cli,
+                    &mut podman,
+                    &mut artifacts,
+                    tag_vars.as_ref(),
+                    oci_opts,
+                    repo,
+                    &hook_ctx,
+                )
+                .await
+                .with_context(|| "Failed to build Podman images".to_string())?;
             }
 
             if let Some(mut docker) = project.docker {
-                build_oci_call(OCIBackend::Docker, cli, &mut docker, &mut artifacts)
-                    .with_context(|| "Failed to build Docker images".to_string())?;
+                let tag_vars =
+                    oci_tag_vars(&docker, project.rpm.as_ref(), &rpm_opts, rpmb_opts).await?;
+                build_oci_call(
+                    OCIBackend::Docker,
+                    cli,
+                    &mut docker,
+                    &mut artifacts,
+                    tag_vars.as_ref(),
+                    oci_opts,
+                    repo,
+                    &hook_ctx,
+                )
+                .await
+                .with_context(|| "Failed to build Docker images".to_string())?;
+            }
+
+            if let Some(mut buildah) = project.buildah {
+                let tag_vars =
+                    oci_tag_vars(&buildah, project.rpm.as_ref(), &rpm_opts, rpmb_opts).await?;
+                build_oci_call(
+                    OCIBackend::Buildah,
+                    cli,
+                    &mut buildah,
+                    &mut artifacts,
+                    tag_vars.as_ref(),
+                    oci_opts,
+                    repo,
+                    &hook_ctx,
+                )
+                .await
+                .with_context(|| "Failed to build Buildah images".to_string())?;
             }
             if let Some(scripts) = &project.scripts {
                 info!("Running build scripts");
@@ -360,12 +594,13 @@ pub async fn build_project(
         PackageType::Rpm => {
             if let Some(rpmbuild) = &project.rpm {
                 build_rpm_call(
-                    cli,
                     rpm_opts,
                     rpmbuild,
                     rpmb_opts.rpm_builder.into(),
                     &mut artifacts,
                     rpmb_opts,
+                    repo,
+                    &hook_ctx,
                 )
                 .await
                 .with_context(|| "Failed to build RPMs".to_string())?;
@@ -375,23 +610,67 @@ pub async fn build_project(
         }
         PackageType::Docker => {
             if let Some(mut docker) = project.docker {
-                build_oci_call(OCIBackend::Docker, cli, &mut docker, &mut artifacts)
-                    .with_context(|| "Failed to build Docker images".to_string())?;
+                let tag_vars =
+                    oci_tag_vars(&docker, project.rpm.as_ref(), &rpm_opts, rpmb_opts).await?;
+                build_oci_call(
+                    OCIBackend::Docker,
+                    cli,
+                    &mut docker,
+                    &mut artifacts,
+                    tag_vars.as_ref(),
+                    oci_opts,
+                    repo,
+                    &hook_ctx,
+                )
+                .await
+                .with_context(|| "Failed to build Docker images".to_string())?;
             } else {
                 println!("No Docker build defined for project");
             }
         }
         PackageType::Podman => {
             if let Some(mut podman) = project.podman {
-                build_oci_call(OCIBackend::Podman, cli, &mut podman, &mut artifacts)
-                    .with_context(|| "Failed to build Podman images".to_string())?;
+                let tag_vars =
+                    oci_tag_vars(&podman, project.rpm.as_ref(), &rpm_opts, rpmb_opts).await?;
+                build_oci_call(
+                    OCIBackend::Podman,
+                    cli,
+                    &mut podman,
+                    &mut artifacts,
+                    tag_vars.as_ref(),
+                    oci_opts,
+                    repo,
+                    &hook_ctx,
+                )
+                .await
+                .with_context(|| "Failed to build Podman images".to_string())?;
             } else {
                 println!("No Podman build defined for project");
             }
         }
+        PackageType::Buildah => {
+            if let Some(mut buildah) = project.buildah {
+                let tag_vars =
+                    oci_tag_vars(&buildah, project.rpm.as_ref(), &rpm_opts, rpmb_opts).await?;
+                build_oci_call(
+                    OCIBackend::Buildah,
+                    cli,
+                    &mut buildah,
+                    &mut artifacts,
+                    tag_vars.as_ref(),
+                    oci_opts,
+                    repo,
+                    &hook_ctx,
+                )
+                .await
+                .with_context(|| "Failed to build Buildah images".to_string())?;
+            } else {
+                println!("No Buildah build defined for project");
+            }
+        }
         PackageType::Flatpak => {
             if let Some(flatpak) = &project.flatpak {
-                build_flatpak_call(cli, flatpak, &mut artifacts, flatpak_opts.clone())
+                build_flatpak_call(cli, flatpak, &mut artifacts, flatpak_opts.clone(), &hook_ctx)
                     .await
                     .with_context(|| "Failed to build Flatpaks".to_string())?;
             } else {
@@ -401,11 +680,20 @@ pub async fn build_project(
         PackageType::RpmOstree => todo!(),
     }
 
-    for (path, arttype) in artifacts.packages {
+    if let Some(post_script) = &project.post_script {
+        hook!(name, post_script, &hook_ctx,);
+    }
+
+    if oci_opts.push {
+        push_artifacts(&mut artifacts).await?;
+    }
+
+    for (path, arttype) in &artifacts.packages {
         let type_string = match arttype {
             PackageType::Rpm => "RPM",
             PackageType::Docker => "Docker image",
             PackageType::Podman => "Podman image",
+            PackageType::Buildah => "Buildah image",
             PackageType::Flatpak => "flatpak",
             PackageType::RpmOstree => "rpm-ostree compose",
             _ => "unknown artifact",
@@ -414,6 +702,24 @@ pub async fn build_project(
         println!("Built {}: {}", type_string, path);
     }
 
+    for (path, checksum) in &artifacts.files {
+        println!("Exported {path} ({checksum})");
+    }
+
+    for (image, path) in &artifacts.sboms {
+        println!("SBOM for {image}: {path}");
+    }
+
+    for (image, pinned) in &artifacts.digests {
+        println!("Pushed {image}: {pinned}");
+    }
+
+    if !artifacts.lints.is_empty() {
+        println!("{} lint finding(s)", artifacts.lints.len());
+    }
+
+    artifacts.save(&cli.target_dir, name)?;
+
     Ok(())
 }
 
@@ -430,21 +736,34 @@ pub async fn builder(
     // todo
     // ? can we assume cli.config won't be modified?
     let config = anda_config::load_from_file(&cli.config.clone())?;
+    let repo = RpmRepo::from_config(cli.target_dir.join("rpm"), config.config.repo.as_ref());
     trace!("all: {all}");
     trace!("project: {project:?}");
     trace!("package: {package:?}");
     if all {
         for (name, project) in config.project {
             println!("Building project: {}", name);
-            build_project(cli, project, package, &rpm_opts, &flatpak_opts, &oci_opts).await?;
+            build_project(cli, &name, project, package, &repo, &rpm_opts, &flatpak_opts, &oci_opts)
+                .await?;
         }
     } else {
         // find project named project
         if let Some(name) = project {
             if let Some(project) = config.get_project(&name) {
+                // resolve aliases to the actual project name
+                let name = config.find_key_for_value(project).unwrap_or(&name);
                 // cannot take: get_project() returns immut ref
-                build_project(cli, project.clone(), package, &rpm_opts, &flatpak_opts, &oci_opts)
-                    .await?;
+                build_project(
+                    cli,
+                    name,
+                    project.clone(),
+                    package,
+                    &repo,
+                    &rpm_opts,
+                    &flatpak_opts,
+                    &oci_opts,
+                )
+                .await?;
             } else {
                 return Err(eyre!("Project not found: {name}"));
             }
@@ -454,3 +773,80 @@ pub async fn builder(
     }
     Ok(())
 }
+
+/// Fetches the sources of a project without building it
+pub async fn fetch(
+    cli: &Cli,
+    project: &str,
+    rpmb_opts: &RpmOpts,
+    flatpak_opts: &FlatpakOpts,
+) -> Result<()> {
+    let config = anda_config::load_from_file(&cli.config)?;
+    let project_cfg =
+        config.get_project(project).ok_or_else(|| eyre!("Project not found: {project}"))?;
+    let name = config.find_key_for_value(project_cfg).map_or(project, |n| n.as_str());
+
+    if let Some(rpmbuild) = &project_cfg.rpm {
+        let mut opts = rpm_options(cli, name, project_cfg, rpmb_opts)?;
+        define_macros(&mut opts, rpmb_opts)?;
+        for source in fetch_sources(&rpmbuild.spec, &opts).await? {
+            println!("Fetched {}", source.display());
+        }
+    }
+
+    if let Some(flatpak) = &project_cfg.flatpak {
+        let builder = flatpak_builder(&cli.target_dir, flatpak, &mut flatpak_opts.clone());
+        for manifest in std::iter::once(&flatpak.manifest).chain(flatpak.extra_manifest.keys()) {
+            let built = builder.download(manifest).await?;
+            println!("Fetched sources for {built}");
+        }
+    }
+
+    if project_cfg.rpm.is_none() && project_cfg.flatpak.is_none() {
+        println!("Nothing to fetch for project");
+    }
+
+    Ok(())
+}
+
+/// Drops into a shell in the mock chroot of a project, set up like `build_project` would
+pub async fn shell(cli: &Cli, project: &str, rpmb_opts: &RpmOpts) -> Result<()> {
+    let config = anda_config::load_from_file(&cli.config)?;
+    let project_cfg =
+        config.get_project(project).ok_or_else(|| eyre!("Project not found: {project}"))?;
+    let name = config.find_key_for_value(project_cfg).map_or(project, |n| n.as_str());
+    let rpmbuild =
+        project_cfg.rpm.as_ref().ok_or_else(|| eyre!("No RPM build defined for {name}"))?;
+
+    if let crate::cli::RPMBuilder::Rpmbuild = rpmb_opts.rpm_builder {
+        return Err(eyre!("anda shell needs the mock backend, rpmbuild builds on the host"));
+    }
+
+    let repo = RpmRepo::from_config(cli.target_dir.join("rpm"), config.config.repo.as_ref());
+    let mut opts = rpm_options(cli, name, project_cfg, rpmb_opts)?;
+    prepare_rpm(&mut opts, &rpmbuild.spec, &repo, rpmb_opts).await?;
+
+    MockBackend::from_options(&mut opts).shell(&rpmbuild.spec).await
+}
+
+#[cfg(test)]
+mod test_builder {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_oci_tag_vars() {
+        let opts = RPMOptions::new(None, ".".into(), ".".into());
+        let rpmb_opts = RpmOpts { reproducible: true, ..Default::default() };
+        let mut manifest = Docker::default();
+        manifest.image.insert("anda".to_string(), Default::default());
+        assert!(oci_tag_vars(&manifest, None, &opts, &rpmb_opts).await.unwrap().is_none());
+
+        manifest.image.get_mut("anda").unwrap().tags = Some(vec!["${git.short}".to_string()]);
+        let vars = oci_tag_vars(&manifest, None, &opts, &rpmb_opts).await.unwrap().unwrap();
+        // --reproducible applies to images too, even without an RPM build
+        let autogit = AutoGit::new(true).unwrap();
+        assert_eq!(vars.version, "latest");
+        assert_eq!(vars.git.version, autogit.version);
+        assert_eq!(vars.date, autogit.date);
+    }
+}
@@ -3,6 +3,7 @@
 
 use clap::{Args, Parser, Subcommand, ValueEnum};
 use clap_complete::Shell;
+use serde::{Deserialize, Serialize};
 use std::{path::PathBuf, str::FromStr};
 
 #[derive(ValueEnum, Debug, Clone, Copy, Default)]
@@ -12,11 +13,34 @@ pub enum RPMBuilder {
     Rpmbuild,
 }
 
-#[derive(Copy, Clone, ValueEnum, Debug)]
+/// Isolation method for mock builds
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockIsolation {
+    /// systemd-nspawn containers
+    Nspawn,
+    /// Plain chroot, for when nspawn is unavailable (e.g. inside containers)
+    Simple,
+}
+
+impl FromStr for MockIsolation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nspawn" => Ok(MockIsolation::Nspawn),
+            "simple" => Ok(MockIsolation::Simple),
+            _ => Err(format!("Invalid mock isolation: {s}")),
+        }
+    }
+}
+
+#[derive(Copy, Clone, ValueEnum, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum PackageType {
     Rpm,
     Docker,
     Podman,
+    Buildah,
     Flatpak,
     RpmOstree,
     All,
@@ -30,6 +54,7 @@ impl FromStr for PackageType {
             "rpm" => Ok(PackageType::Rpm),
             "docker" => Ok(PackageType::Docker),
             "podman" => Ok(PackageType::Podman),
+            "buildah" => Ok(PackageType::Buildah),
             "flatpak" => Ok(PackageType::Flatpak),
             "rpm-ostree" => Ok(PackageType::RpmOstree),
             "all" => Ok(PackageType::All),
@@ -81,6 +106,15 @@ pub struct FlatpakOpts {
     /// Flatpak: Do not delete the build directory
     #[clap(long, action)]
     pub flatpak_dont_delete_build_dir: bool,
+
+    /// Flatpak: flatpak-builder state directory (caches, downloads and ccache)
+    /// defaults to `<target_dir>/flatpak/state`
+    #[clap(long, alias = "state-dir")]
+    pub flatpak_state_dir: Option<PathBuf>,
+
+    /// Flatpak: Build without network access, from sources downloaded by `anda fetch`
+    #[clap(long, action)]
+    pub flatpak_offline: bool,
 }
 
 #[derive(Args, Debug, Clone, Default)]
@@ -94,9 +128,15 @@ pub struct OciOpts {
     #[clap(long)]
     pub build_arg: Vec<String>,
 
-    /// OCI: compress the context with gzip
+    /// OCI: compress the context with gzip, for remote Docker daemons (`DOCKER_HOST`)
+    ///
+    /// Files excluded by `.dockerignore` are left out.
     #[clap(long, action)]
     pub compress: bool,
+
+    /// OCI: push the built images to their registries
+    #[clap(long, action)]
+    pub push: bool,
 }
 
 #[derive(Args, Debug, Clone, Default)]
@@ -130,6 +170,54 @@ pub struct RpmOpts {
     /// RPM: Extra repositories to pass to mock
     #[clap(long, short = 'R')]
     pub extra_repos: Vec<String>,
+
+    /// RPM: Mock isolation method
+    #[clap(long, value_enum)]
+    pub mock_isolation: Option<MockIsolation>,
+
+    /// RPM: Container image to create mock's bootstrap chroot from
+    #[clap(long)]
+    pub mock_bootstrap_image: Option<String>,
+
+    /// RPM: Do not clean the mock chroot before building
+    #[clap(long, action)]
+    pub mock_no_clean: bool,
+
+    /// RPM: Keep the mock chroot after building, for debugging
+    #[clap(long, action)]
+    pub mock_no_cleanup_after: bool,
+
+    /// RPM: Disable mock's root cache
+    #[clap(long, action)]
+    pub mock_no_root_cache: bool,
+
+    /// RPM: Disable mock's package manager cache
+    #[clap(long, action)]
+    pub mock_no_package_cache: bool,
+
+    /// RPM: Unique suffix for the mock chroot, so parallel builds on one host don't collide
+    #[clap(long)]
+    pub mock_uniqueext: Option<String>,
+
+    /// RPM: Reproducible build metadata
+    ///
+    /// Derives the `autogit*` macros and `SOURCE_DATE_EPOCH` from the timestamp of the
+    /// current git commit instead of the current date, so rebuilding the same commit
+    /// gives the same versions.
+    #[clap(long, action)]
+    pub reproducible: bool,
+
+    /// RPM: Do not download sources, use the ones from the cache or the sources directory
+    #[clap(long, action)]
+    pub rpm_offline: bool,
+
+    /// RPM: GPG key ID to sign the RPMs and repository metadata with
+    #[clap(long)]
+    pub sign_key: Option<String>,
+
+    /// RPM: GPG home directory to use for signing
+    #[clap(long, env = "GNUPGHOME")]
+    pub gpg_homedir: Option<PathBuf>,
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -165,6 +253,38 @@ pub enum Command {
         #[clap(flatten)]
         oci_opts: OciOpts,
     },
+    /// Fetches the sources of a project without building it
+    ///
+    /// Remote RPM sources are downloaded into the shared cache in `~/.cache/anda`
+    /// and staged under the target directory, so later builds can run with `--rpm-offline`.
+    /// Flatpak sources are downloaded into the flatpak-builder state directory,
+    /// for use with `--flatpak-offline`.
+    Fetch {
+        /// Project to fetch sources for
+        #[clap()]
+        project: String,
+
+        /// Options for RPM builds
+        #[clap(flatten)]
+        rpm_opts: RpmOpts,
+
+        /// Options for Flatpak builds
+        #[clap(flatten)]
+        flatpak_opts: FlatpakOpts,
+    },
+    /// Opens a shell in the mock chroot of a project
+    ///
+    /// The chroot is set up with the same mock config, repositories, macros and config options
+    /// a build would use, with the build dependencies installed and the spec and sources copied in.
+    Shell {
+        /// Project to open a shell for
+        #[clap()]
+        project: String,
+
+        /// Options for RPM builds
+        #[clap(flatten)]
+        rpm_opts: RpmOpts,
+    },
     /// Cleans up the build directory
     Clean,
 
@@ -206,4 +326,58 @@ pub enum Command {
         #[clap(short, long)]
         labels: Option<String>,
     },
+
+    /// Manage the local RPM repository in the target directory
+    Repo {
+        #[clap(subcommand)]
+        command: RepoCommand,
+
+        /// GPG key ID to sign the repository metadata with
+        #[clap(long)]
+        sign_key: Option<String>,
+
+        /// GPG home directory to use for signing
+        #[clap(long, env = "GNUPGHOME")]
+        gpg_homedir: Option<PathBuf>,
+    },
+
+    /// Publish built RPMs to a repository, and push built OCI images
+    ///
+    /// Copies the RPMs recorded in the build reports of the given projects
+    /// (or all projects with a report) to a local directory or rsync destination,
+    /// laid out as `<distro>/<arch>/`, and regenerates the repository metadata there.
+    /// OCI images are pushed to their registries, and their digests recorded in the reports.
+    Publish {
+        /// Projects to publish, defaults to all built projects
+        #[clap()]
+        projects: Vec<String>,
+
+        /// Local directory or rsync destination (`host:path`), defaults to `destination` in the manifest
+        #[clap(short, long)]
+        destination: Option<String>,
+
+        /// Distribution directory name, e.g. `fc37`, detected from the dist tag if not set
+        #[clap(long)]
+        distro: Option<String>,
+
+        /// GPG key ID to sign the repository metadata with
+        #[clap(long)]
+        sign_key: Option<String>,
+
+        /// GPG home directory to use for signing
+        #[clap(long, env = "GNUPGHOME")]
+        gpg_homedir: Option<PathBuf>,
+    },
+}
+
+#[derive(Subcommand, Debug, Clone)]
+pub enum RepoCommand {
+    /// Removes old package versions and updates the repository metadata
+    Prune {
+        /// Number of versions to keep for each package, defaults to `keep` in the manifest
+        #[clap(short, long)]
+        keep: Option<usize>,
+    },
+    /// Regenerates the repository metadata from scratch
+    Rebuild,
 }
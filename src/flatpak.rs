@@ -1,32 +1,63 @@
 #![allow(dead_code)]
-use crate::util::CommandLog;
-use color_eyre::Report;
+use crate::{sign::SignOpts, util::CommandLog};
+use color_eyre::{eyre::eyre, Report};
 use flatpak::application::FlatpakApplication;
 use std::{
-    env,
     fmt::Display,
     path::{Path, PathBuf},
 };
 use tokio::process::Command;
 type Result<T> = std::result::Result<T, Report>;
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum FlatpakArtifact {
+    /// An application
     Ref(String),
+    Extension(String),
+    Runtime(String),
     Bundle(PathBuf),
 }
 
+impl FlatpakArtifact {
+    /// The ID of a built ref, `None` for bundles
+    pub fn id(&self) -> Option<&str> {
+        match self {
+            FlatpakArtifact::Ref(id)
+            | FlatpakArtifact::Extension(id)
+            | FlatpakArtifact::Runtime(id) => Some(id),
+            FlatpakArtifact::Bundle(_) => None,
+        }
+    }
+
+    // The kind of ref `manifest` builds
+    fn from_manifest(manifest: &FlatpakApplication) -> Self {
+        let id = manifest.get_id();
+        if manifest.build_extension.unwrap_or(false) {
+            FlatpakArtifact::Extension(id)
+        } else if manifest.build_runtime.unwrap_or(false) {
+            FlatpakArtifact::Runtime(id)
+        } else {
+            FlatpakArtifact::Ref(id)
+        }
+    }
+}
+
 impl Display for FlatpakArtifact {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             FlatpakArtifact::Ref(ref r) => write!(f, "ref {}", r),
+            FlatpakArtifact::Extension(ref r) => write!(f, "extension {}", r),
+            FlatpakArtifact::Runtime(ref r) => write!(f, "runtime {}", r),
             FlatpakArtifact::Bundle(ref b) => write!(f, "bundle {}", b.display()),
         }
     }
 }
 
 pub struct FlatpakBuilder {
-    // The output directory for the flatpak build
+    // The output directory for the flatpak build, each app is built in a subdirectory
     output_dir: PathBuf,
+    // The flatpak-builder state directory (cache, downloads, ccache)
+    state_dir: PathBuf,
     // The output flatpak repository
     output_repo: PathBuf,
 
@@ -38,17 +69,87 @@ pub struct FlatpakBuilder {
     extra_sources_urls: Vec<String>,
     // extra arguments to pass to flatpak-builder
     extra_args: Vec<String>,
+    // The branch to build, if the manifest does not set one
+    default_branch: Option<String>,
+    // The architecture to build for
+    arch: Option<String>,
+    // GPG key to sign the repository with
+    gpg: Option<SignOpts>,
+    // URL of the runtime's .flatpakrepo, embedded in bundles
+    runtime_repo: Option<String>,
+    // Build only from sources already in the state directory
+    offline: bool,
 }
 
 impl FlatpakBuilder {
-    pub fn new(output_dir: PathBuf, output_repo: PathBuf, bundles_dir: PathBuf) -> Self {
+    pub fn new(
+        output_dir: PathBuf,
+        state_dir: PathBuf,
+        output_repo: PathBuf,
+        bundles_dir: PathBuf,
+    ) -> Self {
         Self {
             output_dir,
+            state_dir,
             output_repo,
             bundles_dir,
             extra_sources: Vec::new(),
             extra_sources_urls: Vec::new(),
             extra_args: Vec::new(),
+            default_branch: None,
+            arch: None,
+            gpg: None,
+            runtime_repo: None,
+            offline: false,
+        }
+    }
+
+    pub fn default_branch(&mut self, branch: String) {
+        self.default_branch = Some(branch);
+    }
+
+    pub fn arch(&mut self, arch: String) {
+        self.arch = Some(arch);
+    }
+
+    pub fn gpg_sign(&mut self, gpg: SignOpts) {
+        self.gpg = Some(gpg);
+    }
+
+    pub fn runtime_repo(&mut self, url: String) {
+        self.runtime_repo = Some(url);
+    }
+
+    /// Build without network access, using only sources downloaded by [`Self::download`]
+    pub fn offline(&mut self, offline: bool) {
+        self.offline = offline;
+    }
+
+    /// The output repository
+    pub fn repo(&self) -> &Path {
+        &self.output_repo
+    }
+
+    /// AppStream metainfo files installed by the build of `id`
+    pub fn metainfo(&self, id: &str) -> Vec<PathBuf> {
+        let share = self.output_dir.join(id).join("files/share");
+        ["metainfo", "appdata"]
+            .iter()
+            .filter_map(|dir| std::fs::read_dir(share.join(dir)).ok())
+            .flatten()
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.extension().is_some_and(|e| e == "xml"))
+            .collect()
+    }
+
+    // Adds the GPG signing options, shared by flatpak-builder and `flatpak build-update-repo`
+    fn gpg_args(&self, cmd: &mut Command) {
+        if let Some(gpg) = &self.gpg {
+            cmd.arg(format!("--gpg-sign={}", gpg.key));
+            if let Some(homedir) = &gpg.homedir {
+                cmd.arg(format!("--gpg-homedir={}", homedir.display()));
+            }
         }
     }
 
@@ -77,25 +178,27 @@ impl FlatpakBuilder {
         self.extra_sources_urls.extend(iter);
     }
 
-    pub async fn build(&self, manifest: &Path) -> Result<String> {
+    // Parses the manifest and sets up a flatpak-builder command for it,
+    // returning the command and the ref it builds
+    fn flatpak_builder(&self, manifest: &Path) -> Result<(Command, FlatpakArtifact)> {
         // we parse the flatpak metadata file
         let flatpak_meta = FlatpakApplication::load_from_file(manifest.display().to_string())
             .map_err(color_eyre::Report::msg)?;
 
         // create the flatpak output folders
-        let output_dir =
-            env::current_dir()?.join(".flatpak-builder/build").join(&flatpak_meta.app_id);
+        let output_dir = self.output_dir.join(flatpak_meta.get_id());
         std::fs::create_dir_all(&output_dir)?;
-        std::fs::create_dir_all(&self.output_repo)?;
+        std::fs::create_dir_all(&self.state_dir)?;
 
-        // build the flatpak
         let mut flatpak = Command::new("flatpak-builder");
-        flatpak
-            .arg(output_dir)
-            .arg(manifest)
-            .arg("--force-clean")
-            .arg("--repo")
-            .arg(&self.output_repo.canonicalize().unwrap());
+        flatpak.arg(output_dir).arg(manifest).arg("--state-dir").arg(&self.state_dir);
+
+        if let Some(branch) = &self.default_branch {
+            flatpak.arg(format!("--default-branch={branch}"));
+        }
+        if let Some(arch) = &self.arch {
+            flatpak.arg(format!("--arch={arch}"));
+        }
 
         // add extra sources
 
@@ -107,30 +210,117 @@ impl FlatpakBuilder {
             flatpak.arg("--extra-sources-url").arg(source);
         }
 
+        Ok((flatpak, FlatpakArtifact::from_manifest(&flatpak_meta)))
+    }
+
+    /// Downloads the sources of `manifest` into the state directory without building it
+    pub async fn download(&self, manifest: &Path) -> Result<FlatpakArtifact> {
+        let (mut flatpak, built) = self.flatpak_builder(manifest)?;
+        flatpak.arg("--download-only");
+        flatpak.log().await?;
+        Ok(built)
+    }
+
+    // The flatpak-builder command that builds `manifest` into the output repository
+    fn build_command(&self, manifest: &Path) -> Result<(Command, FlatpakArtifact)> {
+        let (mut flatpak, built) = self.flatpak_builder(manifest)?;
+        std::fs::create_dir_all(&self.output_repo)?;
+
+        // build the flatpak
+        flatpak.arg("--force-clean").arg("--repo").arg(canonicalize(&self.output_repo)?);
+        self.gpg_args(&mut flatpak);
+
+        if self.offline {
+            flatpak.arg("--disable-download").arg("--disable-updates");
+        }
+
         flatpak.args(&self.extra_args);
+        Ok((flatpak, built))
+    }
+
+    /// Builds `manifest` into the output repository, returning the ref it built
+    pub async fn build(&self, manifest: &Path) -> Result<FlatpakArtifact> {
+        let (mut flatpak, built) = self.build_command(manifest)?;
 
         // run the command
         flatpak.log().await?;
-        Ok(flatpak_meta.app_id)
+        Ok(built)
     }
 
-    pub async fn bundle(&self, app_id: &str) -> Result<PathBuf> {
+    /// Exports a built ref from the output repository as a bundle,
+    /// named `filename` or `<id>.flatpak`
+    pub async fn bundle(&self, built: &FlatpakArtifact, filename: Option<&str>) -> Result<PathBuf> {
+        let (id, runtime) = match built {
+            FlatpakArtifact::Ref(id) => (id, false),
+            FlatpakArtifact::Extension(id) | FlatpakArtifact::Runtime(id) => (id, true),
+            FlatpakArtifact::Bundle(path) => {
+                return Err(eyre!("{} is already a bundle", path.display()))
+            }
+        };
+
         std::fs::create_dir_all(&self.bundles_dir)?;
-        let bundle_path = self.bundles_dir.join(format!("{app_id}.flatpak"));
+        let bundle_path = self
+            .bundles_dir
+            .join(filename.map_or_else(|| format!("{id}.flatpak"), |name| name.to_string()));
 
         let mut flatpak = Command::new("flatpak");
 
-        flatpak
-            .arg("build-bundle")
-            .arg(&self.output_repo.canonicalize().unwrap())
-            .arg(&bundle_path)
-            .arg(app_id);
+        flatpak.arg("build-bundle");
+        if let Some(arch) = &self.arch {
+            flatpak.arg(format!("--arch={arch}"));
+        }
+        if runtime {
+            flatpak.arg("--runtime");
+        } else if let Some(url) = &self.runtime_repo {
+            flatpak.arg(format!("--runtime-repo={url}"));
+        }
+        flatpak.arg(canonicalize(&self.output_repo)?).arg(&bundle_path).arg(id);
+        if let Some(branch) = &self.default_branch {
+            flatpak.arg(branch);
+        }
 
         flatpak.log().await?;
 
         Ok(bundle_path)
     }
+
+    /// Regenerates the summary (and its signature) of the output repository,
+    /// so it can be served as a Flatpak remote
+    pub async fn update_repo(&self) -> Result<()> {
+        let mut flatpak = Command::new("flatpak");
+        flatpak.arg("build-update-repo");
+        self.gpg_args(&mut flatpak);
+        flatpak.arg(canonicalize(&self.output_repo)?);
+
+        flatpak.log().await
+    }
+}
+
+fn canonicalize(path: &Path) -> Result<PathBuf> {
+    path.canonicalize().map_err(|e| eyre!("Cannot resolve {}: {e}", path.display()))
 }
 
 #[cfg(test)]
-mod test_super {}
+mod test_super {
+    use super::*;
+
+    #[test]
+    fn test_offline_build() {
+        let out = tempfile::tempdir().unwrap();
+        let mut builder = FlatpakBuilder::new(
+            out.path().join("build"),
+            out.path().join("state"),
+            out.path().join("repo"),
+            out.path().join("bundles"),
+        );
+        builder.offline(true);
+
+        let manifest = Path::new("tests/org.flatpak.Hello.yml");
+        let (cmd, built) = builder.build_command(manifest).unwrap();
+        let args = cmd.as_std().get_args().map(|a| a.to_string_lossy()).collect::<Vec<_>>();
+        assert_eq!(built, FlatpakArtifact::Ref("org.flatpak.Hello".to_string()));
+        assert!(args.contains(&"--disable-download".into()));
+        assert!(args.contains(&"--disable-updates".into()));
+        assert!(out.path().join("state").exists());
+    }
+}
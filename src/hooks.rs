@@ -0,0 +1,159 @@
+//! Pre/post build hooks
+//!
+//! Hooks are either `.rhai` scripts, run with andax, shell scripts, or inline shell commands.
+//! All of them get the project's environment and some context about the build:
+//! - shell hooks get `ANDA_PROJECT` and `ANDA_TARGET` (the spec or manifest being built)
+//!   on top of the project's `env`
+//! - rhai hooks get `project`, `target` and `env` in their scope
+
+use crate::util::CommandLog;
+use anda_config::Hook;
+use andax::Scope;
+use color_eyre::{eyre::eyre, Result};
+use std::{
+    collections::BTreeMap,
+    os::unix::fs::PermissionsExt,
+    path::{Path, PathBuf},
+};
+use tokio::process::Command;
+use tracing::{error, info};
+
+/// What a hook is run for
+#[derive(Clone, Debug, Default)]
+pub struct HookContext {
+    /// Name of the project
+    pub project: String,
+    /// Environment variables from the project
+    pub env: BTreeMap<String, String>,
+    /// Labels from the project, passed to rhai scripts
+    pub labels: BTreeMap<String, String>,
+}
+
+impl HookContext {
+    pub fn new(project: &str, config: &anda_config::Project) -> Self {
+        Self {
+            project: project.to_string(),
+            env: config.env.clone().unwrap_or_default(),
+            labels: config.labels.clone(),
+        }
+    }
+
+    fn envs(&self, target: &str) -> BTreeMap<String, String> {
+        let mut env = self.env.clone();
+        env.insert("ANDA_PROJECT".to_string(), self.project.clone());
+        env.insert("ANDA_TARGET".to_string(), target.to_string());
+        env
+    }
+}
+
+/// Runs `hook` for `target`, e.g. the spec or manifest being built.
+///
+/// `scope` can push extra variables into the scope of rhai scripts,
+/// which is returned afterwards so changes to them can be read back.
+/// For shell hooks, `Ok(None)` is returned.
+pub async fn run_hook<'a>(
+    target: &'a str,
+    hook: &'a Hook,
+    ctx: &HookContext,
+    scope: impl FnOnce(&mut Scope<'a>),
+) -> Result<Option<Scope<'a>>> {
+    match hook {
+        Hook::Script(path) if path.extension().is_some_and(|e| e == "rhai") => {
+            let env = ctx.env.iter().map(|(k, v)| (k.into(), v.into())).collect::<andax::Map>();
+            let project = ctx.project.clone();
+            let sc = andax::run(target, path, ctx.labels.clone(), |sc| {
+                sc.push("project", project);
+                sc.push("target", target.to_string());
+                sc.push("env", env);
+                scope(sc);
+            });
+            match sc {
+                Some(sc) => Ok(Some(sc)),
+                None => {
+                    error!(
+                        scr = path.display().to_string(),
+                        "Hook failed with aforementioned exception."
+                    );
+                    Err(eyre!("Hook {} failed", path.display()))
+                }
+            }
+        }
+        Hook::Script(path) => {
+            info!("Running hook {}", path.display());
+            let mut cmd = script_command(path)?;
+            cmd.envs(ctx.envs(target));
+            cmd.log().await?;
+            Ok(None)
+        }
+        Hook::Commands { commands, cwd } => {
+            for command in commands {
+                info!("Running hook `{command}`");
+                let mut cmd = Command::new("sh");
+                cmd.arg("-c").arg(command).envs(ctx.envs(target));
+                if let Some(cwd) = cwd {
+                    cmd.current_dir(cwd);
+                }
+                cmd.log().await?;
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Executable scripts are run directly so their shebang is honoured, anything else with `sh`
+fn script_command(path: &Path) -> Result<Command> {
+    let meta =
+        std::fs::metadata(path).map_err(|e| eyre!("Cannot run hook {}: {e}", path.display()))?;
+    if meta.permissions().mode() & 0o111 != 0 {
+        // a bare file name would be looked up in $PATH
+        let path = if path.is_relative() { Path::new(".").join(path) } else { PathBuf::from(path) };
+        Ok(Command::new(path))
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg(path);
+        Ok(cmd)
+    }
+}
+
+#[cfg(test)]
+mod test_hooks {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_shell_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = HookContext { project: "hello".to_string(), ..Default::default() };
+
+        let hook = Hook::Commands {
+            commands: vec!["echo \"$ANDA_PROJECT $ANDA_TARGET\" > out".to_string()],
+            cwd: Some(dir.path().to_path_buf()),
+        };
+        assert!(run_hook("hello.spec", &hook, &ctx, |_| {}).await.unwrap().is_none());
+        let out = std::fs::read_to_string(dir.path().join("out")).unwrap();
+        assert_eq!(out.trim(), "hello hello.spec");
+
+        let script = dir.path().join("fail.sh");
+        std::fs::write(&script, "exit 1\n").unwrap();
+        assert!(run_hook("hello.spec", &Hook::Script(script), &ctx, |_| {}).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_rhai_hooks() {
+        let dir = tempfile::tempdir().unwrap();
+        let ctx = HookContext { project: "hello".to_string(), ..Default::default() };
+        let script = Hook::Script(dir.path().join("pre.rhai"));
+        std::fs::write(
+            dir.path().join("pre.rhai"),
+            "if project != \"hello\" { throw \"?\" }\nx += 1;",
+        )
+        .unwrap();
+
+        let sc = run_hook("hello.spec", &script, &ctx, |sc| {
+            sc.push("x", 1_i64);
+        })
+        .await
+        .unwrap()
+        .unwrap();
+        assert_eq!(sc.get_value::<i64>("x"), Some(2));
+    }
+}
@@ -0,0 +1,265 @@
+//! Post-build lint checks
+//!
+//! Linters are run over the built artifacts, and their findings are attached
+//! to the build report.
+
+use anda_config::Severity;
+use color_eyre::{eyre::eyre, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+use tokio::process::Command;
+use tracing::debug;
+
+lazy_static! {
+    // <target>[:<line>]: <E|W|I>: <check> [details]
+    static ref RPMLINT_REGEX: Regex =
+        Regex::new(r"^(\S+?):(?:\d+:)?\s+([EWI]):\s+(\S+)\s*(.*)$").unwrap();
+    // <E|W|I|P>: <component>[:<line>|~]: <check> [details]
+    static ref APPSTREAM_REGEX: Regex =
+        Regex::new(r"^([EWIP]):\s+(\S+?)(?::(?:\d+|~))?:\s+(\S+)\s*(.*)$").unwrap();
+}
+
+/// Maps the one-letter severity codes used by rpmlint and appstreamcli
+fn severity_code(code: &str) -> Option<Severity> {
+    match code {
+        "I" => Some(Severity::Info),
+        "W" => Some(Severity::Warning),
+        "E" => Some(Severity::Error),
+        _ => None,
+    }
+}
+
+/// A single lint finding
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Finding {
+    /// The linter that reported this finding
+    pub tool: String,
+    pub severity: Severity,
+    /// What the finding is about, e.g. a package name
+    pub target: String,
+    /// The name of the check that failed
+    pub check: String,
+    pub message: String,
+}
+
+impl Display for Finding {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {} [{}] {}", self.tool, self.target, self.severity, self.check)?;
+        if !self.message.is_empty() {
+            write!(f, ": {}", self.message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns an error if any of the findings are at least as severe as `threshold`
+pub fn check_threshold(findings: &[Finding], threshold: Severity) -> Result<()> {
+    let failed = findings.iter().filter(|f| f.severity >= threshold).collect::<Vec<&Finding>>();
+    if failed.is_empty() {
+        return Ok(());
+    }
+
+    let mut msg = format!("{} lint finding(s) at or above {threshold}:", failed.len());
+    for finding in failed {
+        msg += &format!("\n  {finding}");
+    }
+    Err(eyre!(msg))
+}
+
+/// Runs rpmlint over `rpms`
+///
+/// rpmlint exits with a non-zero status whenever it finds errors,
+/// so we only fail here if it could not be run at all.
+pub async fn rpmlint(rpms: &[PathBuf], rpmlintrc: Option<&Path>) -> Result<Vec<Finding>> {
+    let mut cmd = Command::new("rpmlint");
+    if let Some(rc) = rpmlintrc {
+        cmd.arg("-r").arg(rc);
+    }
+    cmd.args(rpms);
+
+    debug!("Running rpmlint on {rpms:?}");
+    let output = cmd.output().await.map_err(|e| eyre!("Failed to run rpmlint: {e}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let findings = parse_rpmlint(&stdout);
+
+    if !output.status.success() && findings.is_empty() {
+        return Err(eyre!(
+            "rpmlint exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(findings)
+}
+
+fn parse_rpmlint(output: &str) -> Vec<Finding> {
+    output
+        .lines()
+        .filter_map(|line| RPMLINT_REGEX.captures(line))
+        .filter_map(|cap| {
+            Some(Finding {
+                tool: "rpmlint".to_string(),
+                severity: severity_code(&cap[2])?,
+                target: cap[1].to_string(),
+                check: cap[3].to_string(),
+                message: cap[4].to_string(),
+            })
+        })
+        .collect()
+}
+
+/// Runs `flatpak-builder-lint` on a manifest or repository (`kind` is `manifest` or `repo`)
+pub async fn flatpak_builder_lint(kind: &str, path: &Path) -> Result<Vec<Finding>> {
+    debug!("Running flatpak-builder-lint on {kind} {}", path.display());
+    let output = Command::new("flatpak-builder-lint")
+        .arg(kind)
+        .arg(path)
+        .output()
+        .await
+        .map_err(|e| eyre!("Failed to run flatpak-builder-lint: {e}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+
+    // like rpmlint, it exits with a non-zero status when it finds errors
+    if stdout.trim().is_empty() {
+        if output.status.success() {
+            return Ok(Vec::new());
+        }
+        return Err(eyre!(
+            "flatpak-builder-lint exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    parse_flatpak_builder_lint(&path.display().to_string(), &stdout)
+}
+
+fn parse_flatpak_builder_lint(target: &str, output: &str) -> Result<Vec<Finding>> {
+    #[derive(Deserialize)]
+    struct Report {
+        #[serde(default)]
+        errors: Vec<String>,
+        #[serde(default)]
+        warnings: Vec<String>,
+        #[serde(default)]
+        info: Vec<String>,
+    }
+
+    let report: Report = serde_json::from_str(output)
+        .map_err(|e| eyre!("Cannot parse flatpak-builder-lint output: {e}"))?;
+    let findings = [
+        (Severity::Error, report.errors),
+        (Severity::Warning, report.warnings),
+        (Severity::Info, report.info),
+    ]
+    .into_iter()
+    .flat_map(|(severity, checks)| {
+        checks.into_iter().map(move |check| Finding {
+            tool: "flatpak-builder-lint".to_string(),
+            severity,
+            target: target.to_string(),
+            check,
+            message: String::new(),
+        })
+    })
+    .collect();
+    Ok(findings)
+}
+
+/// Runs `appstreamcli validate` on a metainfo file
+pub async fn appstream_validate(metainfo: &Path) -> Result<Vec<Finding>> {
+    debug!("Validating {}", metainfo.display());
+    let output = Command::new("appstreamcli")
+        .arg("validate")
+        .arg("--no-net")
+        .arg(metainfo)
+        .output()
+        .await
+        .map_err(|e| eyre!("Failed to run appstreamcli: {e}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let findings = parse_appstream(&stdout);
+
+    if !output.status.success() && findings.is_empty() {
+        return Err(eyre!(
+            "appstreamcli exited with {}: {}",
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+
+    Ok(findings)
+}
+
+fn parse_appstream(output: &str) -> Vec<Finding> {
+    output
+        .lines()
+        .filter_map(|line| APPSTREAM_REGEX.captures(line))
+        .filter_map(|cap| {
+            Some(Finding {
+                tool: "appstreamcli".to_string(),
+                // pedantic hints are only shown with --pedantic, but treat them as info anyway
+                severity: if &cap[1] == "P" { Severity::Info } else { severity_code(&cap[1])? },
+                target: cap[2].to_string(),
+                check: cap[3].to_string(),
+                message: cap[4].to_string(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test_lint {
+    use super::*;
+
+    #[test]
+    fn test_parse_rpmlint() {
+        let output = "============================ rpmlint session starts ============================\n\
+                      rpmlint: 2.4.0\n\
+                      umpkg.noarch: W: no-manual-page-for-binary umpkg\n\
+                      umpkg.src:12: E: hardcoded-library-path in %{_prefix}/lib\n\
+                      2 packages and 0 specfiles checked; 1 errors, 1 warnings, 0 badness; has taken 0.3 s\n";
+
+        let findings = parse_rpmlint(output);
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].severity, Severity::Warning);
+        assert_eq!(findings[0].target, "umpkg.noarch");
+        assert_eq!(findings[0].check, "no-manual-page-for-binary");
+        assert_eq!(findings[0].message, "umpkg");
+        assert_eq!(findings[1].severity, Severity::Error);
+        assert_eq!(findings[1].target, "umpkg.src");
+
+        assert!(check_threshold(&findings, Severity::Error).is_err());
+        assert!(check_threshold(&findings[..1], Severity::Error).is_ok());
+    }
+
+    #[test]
+    fn test_parse_flatpak_lints() {
+        let output = r#"{"errors": ["finish-args-unnecessary-xdg-data-access"], "warnings": ["appstream-screenshots-not-mirrored"], "message": "Please consult the documentation"}"#;
+        let findings = parse_flatpak_builder_lint("org.flatpak.Hello.yml", output).unwrap();
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].severity, Severity::Error);
+        assert_eq!(findings[1].check, "appstream-screenshots-not-mirrored");
+
+        let output = "I: org.example.Foo:5: summary-has-dot-suffix A foo.\n\
+                      E: org.example.Foo:~: app-description-required\n\
+                      \n\
+                      ✘ Validation failed: errors: 1, infos: 1\n";
+        let findings = parse_appstream(output);
+        assert_eq!(findings.len(), 2);
+        assert_eq!(findings[0].severity, Severity::Info);
+        assert_eq!(findings[0].message, "A foo.");
+        assert_eq!(findings[1].severity, Severity::Error);
+        assert_eq!(findings[1].target, "org.example.Foo");
+        assert_eq!(findings[1].check, "app-description-required");
+        assert_eq!(
+            findings[1].to_string(),
+            "appstreamcli: org.example.Foo [error] app-description-required"
+        );
+    }
+}
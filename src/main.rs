@@ -4,8 +4,15 @@ mod artifacts;
 mod builder;
 mod cli;
 mod flatpak;
+mod hooks;
+mod lint;
+mod mock_log;
 mod oci;
+mod publish;
+mod repo;
 mod rpm_spec;
+mod sign;
+mod sources;
 mod update;
 mod util;
 use anda_config::parse_map;
@@ -57,6 +64,12 @@ async fn main() -> Result<()> {
             builder::builder(&cli, rpm_opts, all, project, package, flatpak_opts, oci_opts)
                 .await?;
         }
+        Command::Fetch { ref project, ref rpm_opts, ref flatpak_opts } => {
+            builder::fetch(&cli, project, rpm_opts, flatpak_opts).await?;
+        }
+        Command::Shell { ref project, ref rpm_opts } => {
+            builder::shell(&cli, project, rpm_opts).await?;
+        }
         Command::Clean => {
             println!("Cleaning up build directory");
             let clean = std::fs::remove_dir_all(&cli.target_dir);
@@ -120,6 +133,30 @@ async fn main() -> Result<()> {
             };
             update::run_scripts(&scripts, labels)?;
         }
+        Command::Repo { command, sign_key, gpg_homedir } => {
+            let config = util::load_manifest_opt(&cli.config)?;
+            let sign = sign_key.map(|key| sign::SignOpts::new(key, gpg_homedir));
+            repo::repo_command(
+                &cli.target_dir,
+                config.as_ref().and_then(|c| c.config.repo.as_ref()),
+                command,
+                sign,
+            )
+            .await?;
+        }
+        Command::Publish { projects, destination, distro, sign_key, gpg_homedir } => {
+            let config = util::load_manifest_opt(&cli.config)?;
+            let sign = sign_key.map(|key| sign::SignOpts::new(key, gpg_homedir));
+            publish::publish(
+                &cli.target_dir,
+                &projects,
+                config.as_ref().and_then(|c| c.config.publish.as_ref()),
+                destination,
+                distro,
+                sign,
+            )
+            .await?;
+        }
     }
     Ok(())
 }
@@ -0,0 +1,241 @@
+//! Mock build log handling
+//!
+//! Mock writes `build.log`, `root.log` and `state.log` into its result directory.
+//! This module keeps those logs around after a build and tries to figure out
+//! which part of the build failed, so we can report something more useful than
+//! an exit status.
+
+use color_eyre::Result;
+use lazy_static::lazy_static;
+use regex::Regex;
+use std::{
+    fmt::Display,
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+/// Log files produced by mock that we want to keep
+pub const MOCK_LOGS: &[&str] = &["build.log", "root.log", "state.log"];
+
+/// Maximum amount of log lines to include in a failure summary
+const SUMMARY_LINES: usize = 15;
+
+lazy_static! {
+    static ref EXECUTING_REGEX: Regex = Regex::new(r"Executing\(%(\w+)\)").unwrap();
+    static ref BAD_EXIT_REGEX: Regex = Regex::new(r"Bad exit status from \S+ \(%(\w+)\)").unwrap();
+    static ref ROOT_LOG_PREFIX_REGEX: Regex =
+        Regex::new(r"^(DEBUG|INFO|WARNING|ERROR) \S+:\d+:\s*").unwrap();
+    static ref DEP_ERROR_REGEX: Regex = Regex::new(
+        r"(No matching package to install|nothing provides|conflicts with|Problem( \d+)?:|^Error:)"
+    )
+    .unwrap();
+}
+
+/// The section of the build that failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BuildStage {
+    /// Installing the build dependencies into the chroot
+    Dependencies,
+    Prep,
+    GenerateBuildRequires,
+    Build,
+    Install,
+    Check,
+    Unknown,
+}
+
+impl BuildStage {
+    fn from_section(section: &str) -> Self {
+        match section {
+            "prep" => BuildStage::Prep,
+            "generate_buildrequires" => BuildStage::GenerateBuildRequires,
+            "build" => BuildStage::Build,
+            "install" => BuildStage::Install,
+            "check" => BuildStage::Check,
+            _ => BuildStage::Unknown,
+        }
+    }
+}
+
+impl Display for BuildStage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BuildStage::Dependencies => write!(f, "dependency resolution"),
+            BuildStage::Prep => write!(f, "%prep"),
+            BuildStage::GenerateBuildRequires => write!(f, "%generate_buildrequires"),
+            BuildStage::Build => write!(f, "%build"),
+            BuildStage::Install => write!(f, "%install"),
+            BuildStage::Check => write!(f, "%check"),
+            BuildStage::Unknown => write!(f, "an unknown stage"),
+        }
+    }
+}
+
+/// A summary of a failed mock build
+#[derive(Debug, Clone)]
+pub struct BuildFailure {
+    pub stage: BuildStage,
+    /// The log the summary was taken from
+    pub log: PathBuf,
+    /// The last relevant lines of the log
+    pub lines: Vec<String>,
+}
+
+impl Display for BuildFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Build failed during {} (see {})", self.stage, self.log.display())?;
+        for line in &self.lines {
+            write!(f, "\n  {line}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Copies mock's log files from `resultdir` into `dest`
+pub fn preserve_logs(resultdir: &Path, dest: &Path) -> Result<()> {
+    std::fs::create_dir_all(dest)?;
+    for log in MOCK_LOGS {
+        let src = resultdir.join(log);
+        if src.exists() {
+            debug!("Preserving {} in {}", src.display(), dest.display());
+            std::fs::copy(&src, dest.join(log))?;
+        }
+    }
+    Ok(())
+}
+
+/// Inspects the logs in `logdir` and works out why the build failed
+///
+/// Returns `None` if there are no logs to look at.
+pub fn analyze_logs(logdir: &Path) -> Option<BuildFailure> {
+    let read = |name: &str| std::fs::read_to_string(logdir.join(name)).ok();
+    let build_log = read("build.log");
+    let root_log = read("root.log");
+
+    if let Some(build_log) = &build_log {
+        if let Some((stage, lines)) = failed_section(build_log) {
+            return Some(BuildFailure { stage, log: logdir.join("build.log"), lines });
+        }
+    }
+
+    if let Some(root_log) = &root_log {
+        let lines = dependency_errors(root_log);
+        if !lines.is_empty() {
+            return Some(BuildFailure {
+                stage: BuildStage::Dependencies,
+                log: logdir.join("root.log"),
+                lines,
+            });
+        }
+    }
+
+    if let Some(build_log) = &build_log {
+        if !build_log.trim().is_empty() {
+            let stage = last_section(build_log).unwrap_or(BuildStage::Unknown);
+            let lines = tail(build_log.lines(), SUMMARY_LINES);
+            return Some(BuildFailure { stage, log: logdir.join("build.log"), lines });
+        }
+    }
+
+    root_log.map(|root_log| BuildFailure {
+        stage: BuildStage::Unknown,
+        log: logdir.join("root.log"),
+        lines: tail(root_log.lines().map(strip_root_prefix), SUMMARY_LINES),
+    })
+}
+
+/// Finds the section rpmbuild bailed out of, along with the lines leading up to it
+fn failed_section(build_log: &str) -> Option<(BuildStage, Vec<String>)> {
+    let lines = build_log.lines().collect::<Vec<&str>>();
+    let (idx, stage) = lines.iter().enumerate().rev().find_map(|(i, line)| {
+        BAD_EXIT_REGEX.captures(line).map(|cap| (i, BuildStage::from_section(&cap[1])))
+    })?;
+
+    // skip the `RPM build errors:` footer, rpmbuild repeats the bad exit status there
+    let end = lines[..=idx]
+        .iter()
+        .rposition(|line| line.starts_with("RPM build errors:"))
+        .unwrap_or(idx + 1);
+
+    Some((stage, tail(lines[..end].iter().copied(), SUMMARY_LINES)))
+}
+
+/// Returns the last `%section` rpmbuild started executing
+fn last_section(build_log: &str) -> Option<BuildStage> {
+    build_log
+        .lines()
+        .rev()
+        .find_map(|line| EXECUTING_REGEX.captures(line))
+        .map(|cap| BuildStage::from_section(&cap[1]))
+}
+
+/// Collects dependency resolution errors from mock's root.log
+fn dependency_errors(root_log: &str) -> Vec<String> {
+    let errors = root_log
+        .lines()
+        .map(strip_root_prefix)
+        .filter(|line| DEP_ERROR_REGEX.is_match(line.trim_start()))
+        .map(|line| line.trim().to_string())
+        .collect::<Vec<String>>();
+    tail(errors, SUMMARY_LINES)
+}
+
+fn strip_root_prefix(line: &str) -> &str {
+    ROOT_LOG_PREFIX_REGEX.find(line).map_or(line, |m| &line[m.end()..])
+}
+
+/// Last `n` non-empty lines
+fn tail<I, S>(lines: I, n: usize) -> Vec<String>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let lines = lines
+        .into_iter()
+        .filter(|l| !l.as_ref().trim().is_empty())
+        .map(|l| l.as_ref().to_string())
+        .collect::<Vec<String>>();
+    lines[lines.len().saturating_sub(n)..].to_vec()
+}
+
+#[cfg(test)]
+mod test_mock_log {
+    use super::*;
+
+    #[test]
+    fn test_failed_section() {
+        let log = "Executing(%prep): /bin/sh -e /var/tmp/rpm-tmp.abc\n\
+                   + cd /builddir/build/BUILD\n\
+                   Executing(%build): /bin/sh -e /var/tmp/rpm-tmp.def\n\
+                   main.c:1:10: fatal error: foo.h: No such file or directory\n\
+                   error: Bad exit status from /var/tmp/rpm-tmp.def (%build)\n\
+                   \n\
+                   RPM build errors:\n    \
+                   Bad exit status from /var/tmp/rpm-tmp.def (%build)\n";
+
+        let (stage, lines) = failed_section(log).unwrap();
+        assert_eq!(stage, BuildStage::Build);
+        assert_eq!(
+            lines.last().unwrap(),
+            "error: Bad exit status from /var/tmp/rpm-tmp.def (%build)"
+        );
+        assert_eq!(last_section(log), Some(BuildStage::Build));
+    }
+
+    #[test]
+    fn test_dependency_errors() {
+        let log = "DEBUG util.py:444:  Updating and loading repositories:\n\
+                   DEBUG util.py:444:  No matching package to install: 'foo-devel'\n\
+                   DEBUG util.py:444:  Not all dependencies satisfied\n\
+                   DEBUG util.py:446:  Error: Some packages could not be found.\n";
+
+        assert_eq!(
+            dependency_errors(log),
+            vec![
+                "No matching package to install: 'foo-devel'".to_string(),
+                "Error: Some packages could not be found.".to_string()
+            ]
+        );
+        assert!(failed_section(log).is_none());
+    }
+}
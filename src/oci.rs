@@ -1,11 +1,37 @@
 //! OCI Builder backend
-//! Supports Docker and Podman
-use std::process::Command;
+//! Supports Docker, Podman and Buildah
+use crate::{
+    artifacts::Artifacts,
+    cli::{OciOpts, PackageType},
+    util::{get_commit_id_cwd, get_commit_time_cwd, get_remote_url_cwd, CommandLog},
+};
+use anda_config::{template::render_template, DockerImage};
+use chrono::{SecondsFormat, TimeZone, Utc};
+use color_eyre::{eyre::eyre, Report, Result};
+use flate2::{write::GzEncoder, Compression};
+use globset::{GlobBuilder, GlobMatcher};
+use serde::Serialize;
+use std::{
+    fs::File,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
 
-#[derive(Clone, Copy)]
+/// Where the Dockerfile is put in a compressed context
+const CONTEXT_DOCKERFILE: &str = ".anda.Dockerfile";
+/// Where the local RPM repository is mounted in builds
+const CONTAINER_RPM_REPO: &str = "/run/anda/rpm";
+/// The `.repo` file for the mounted repository, written into the repository root
+const CONTAINER_REPO_FILE: &str = ".anda-container.repo";
+
+#[derive(Clone, Copy, Debug)]
 pub enum OCIBackend {
     Docker,
     Podman,
+    /// Daemonless and rootless builds with `buildah bud`
+    Buildah,
 }
 
 impl OCIBackend {
@@ -13,10 +39,353 @@ impl OCIBackend {
         let cmd = match self {
             OCIBackend::Docker => "docker",
             OCIBackend::Podman => "podman",
+            OCIBackend::Buildah => "buildah",
         };
 
         Command::new(cmd)
     }
+
+    /// The backend that built images of this type
+    pub fn for_package(kind: PackageType) -> Option<Self> {
+        match kind {
+            PackageType::Docker => Some(OCIBackend::Docker),
+            PackageType::Podman => Some(OCIBackend::Podman),
+            PackageType::Buildah => Some(OCIBackend::Buildah),
+            _ => None,
+        }
+    }
+
+    /// The subcommand that builds an image from a Dockerfile
+    fn build_command(&self) -> &'static str {
+        match self {
+            OCIBackend::Buildah => "bud",
+            _ => "build",
+        }
+    }
+}
+
+/// Format of an exported image archive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Oci,
+    Docker,
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oci-archive" | "oci" => Ok(ArchiveFormat::Oci),
+            "docker-archive" | "docker" => Ok(ArchiveFormat::Docker),
+            _ => Err(eyre!("Invalid archive format `{s}`, expected oci-archive or docker-archive")),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    /// Detects the format of an image archive, OCI archives have an `oci-layout` file
+    pub fn detect(path: &Path) -> Result<Self> {
+        let mut archive = tar::Archive::new(File::open(path)?);
+        for entry in archive.entries()? {
+            if entry?.path()?.as_ref() == Path::new("oci-layout") {
+                return Ok(ArchiveFormat::Oci);
+            }
+        }
+        Ok(ArchiveFormat::Docker)
+    }
+
+    /// The containers-transports name of the format
+    fn transport(&self) -> &'static str {
+        match self {
+            ArchiveFormat::Oci => "oci-archive",
+            ArchiveFormat::Docker => "docker-archive",
+        }
+    }
+}
+
+/// Extra options for image builds
+#[derive(Clone, Debug, Default)]
+pub struct BuildOpts {
+    /// `KEY=VALUE`, or `KEY` to take the value from the environment
+    pub build_args: Vec<String>,
+    /// Stage to build in a multi-stage Dockerfile
+    pub target: Option<String>,
+    pub cache_from: Vec<String>,
+    pub cache_to: Vec<String>,
+    /// `id=<id>,src=<file>` or `id=<id>,env=<variable>`
+    pub secrets: Vec<String>,
+    pub network: Option<String>,
+    /// Pull policy: `always`, `missing`, `never` or `newer`
+    pub pull: Option<String>,
+    /// Local RPM repository to expose to the build
+    pub rpm_repo: Option<PathBuf>,
+}
+
+impl BuildOpts {
+    /// The build options of `image`, with the build arguments from the command line taking precedence
+    pub fn new(
+        backend: OCIBackend,
+        image: &DockerImage,
+        cli_build_args: &[String],
+    ) -> Result<Self> {
+        let mut build_args = image.build_args.clone();
+        let mut from_env = Vec::new();
+        for arg in cli_build_args {
+            match arg.split_once('=') {
+                Some((key, value)) => {
+                    build_args.insert(key.to_string(), value.to_string());
+                }
+                None => {
+                    build_args.remove(arg);
+                    from_env.push(arg.clone());
+                }
+            }
+        }
+
+        let mut secrets = Vec::new();
+        for (id, secret) in &image.secret {
+            let secret = match (&secret.file, &secret.env) {
+                (Some(file), None) => format!("id={id},src={}", file.display()),
+                (None, Some(env)) => format!("id={id},env={env}"),
+                _ => return Err(eyre!("Secret {id} needs either a file or an env variable")),
+            };
+            secrets.push(secret);
+        }
+
+        if let Some(pull) = &image.pull {
+            match (backend, pull.as_str()) {
+                (OCIBackend::Docker, "always" | "missing") => {}
+                (OCIBackend::Docker, _) => {
+                    return Err(eyre!("Docker only supports the always and missing pull policies"))
+                }
+                (_, "always" | "missing" | "never" | "newer") => {}
+                _ => return Err(eyre!("Invalid pull policy: {pull}")),
+            }
+        }
+
+        Ok(Self {
+            build_args: build_args
+                .into_iter()
+                .map(|(key, value)| format!("{key}={value}"))
+                .chain(from_env)
+                .collect(),
+            target: image.target.clone(),
+            cache_from: image.cache_from.clone().unwrap_or_default(),
+            cache_to: image.cache_to.clone().unwrap_or_default(),
+            secrets,
+            network: image.network.clone(),
+            pull: image.pull.clone(),
+            rpm_repo: None,
+        })
+    }
+
+    /// Exposes the RPM repository in `root` to the build as the `anda-local` dnf repository
+    ///
+    /// Podman and Buildah mount it at [`CONTAINER_RPM_REPO`], with the repository enabled in
+    /// `/etc/yum.repos.d`, so a plain `RUN dnf install` picks up the built packages.
+    ///
+    /// Docker cannot mount anything into a build, so nothing is enabled there.
+    /// The repository is passed as the `anda-rpm` build context instead (this needs BuildKit),
+    /// and the Dockerfile has to mount and enable it itself:
+    ///
+    /// ```dockerfile
+    /// RUN --mount=type=bind,from=anda-rpm,target=/run/anda/rpm \
+    ///     dnf install -y --repofrompath=anda-local,/run/anda/rpm \
+    ///     --setopt=anda-local.gpgcheck=0 <package>
+    /// ```
+    pub fn rpm_repo(&mut self, root: &Path) -> Result<&mut Self> {
+        if !root.join("repodata").exists() {
+            return Err(eyre!(
+                "No RPM repository in {}, the project's RPMs need to be built first",
+                root.display()
+            ));
+        }
+        let root = root.canonicalize()?;
+        std::fs::write(
+            root.join(CONTAINER_REPO_FILE),
+            format!(
+                "[anda-local]\nname=anda local builds\nbaseurl=file://{CONTAINER_RPM_REPO}\n\
+                 enabled=1\ngpgcheck=0\nmetadata_expire=0\nskip_if_unavailable=1\n"
+            ),
+        )?;
+        self.rpm_repo = Some(root);
+        Ok(self)
+    }
+
+    fn args(&self, backend: OCIBackend, cmd: &mut Command) {
+        for arg in &self.build_args {
+            cmd.arg("--build-arg").arg(arg);
+        }
+        if let Some(target) = &self.target {
+            cmd.arg("--target").arg(target);
+        }
+        for cache in &self.cache_from {
+            cmd.arg("--cache-from").arg(cache);
+        }
+        for cache in &self.cache_to {
+            cmd.arg("--cache-to").arg(cache);
+        }
+        for secret in &self.secrets {
+            cmd.arg("--secret").arg(secret);
+        }
+        if let Some(network) = &self.network {
+            cmd.arg("--network").arg(network);
+        }
+        if let Some(root) = &self.rpm_repo {
+            match backend {
+                OCIBackend::Docker => {
+                    cmd.arg("--build-context").arg(format!("anda-rpm={}", root.display()));
+                }
+                _ => {
+                    let repo_file = root.join(CONTAINER_REPO_FILE);
+                    cmd.arg("--volume")
+                        .arg(format!("{}:{CONTAINER_RPM_REPO}:ro,z", root.display()));
+                    cmd.arg("--volume").arg(format!(
+                        "{}:/etc/yum.repos.d/anda-local.repo:ro,z",
+                        repo_file.display()
+                    ));
+                }
+            }
+        }
+        match (backend, self.pull.as_deref()) {
+            (_, None) | (OCIBackend::Docker, Some("missing")) => {}
+            // `docker build --pull` only means always
+            (OCIBackend::Docker, Some(_)) => {
+                cmd.arg("--pull");
+            }
+            (_, Some(pull)) => {
+                cmd.arg(format!("--pull={pull}"));
+            }
+        }
+    }
+}
+
+/// Patterns from a `.dockerignore` file
+///
+/// Like Docker, patterns are relative to the context root, `*` does not match `/`,
+/// a matching directory excludes everything in it, and the last matching pattern wins.
+pub struct DockerIgnore {
+    patterns: Vec<(GlobMatcher, bool)>,
+}
+
+impl DockerIgnore {
+    pub fn parse(dockerignore: &str) -> Result<Self> {
+        let mut patterns = Vec::new();
+        for line in dockerignore.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (pattern, negate) = match line.strip_prefix('!') {
+                Some(pattern) => (pattern.trim(), true),
+                None => (line, false),
+            };
+            let pattern = pattern.trim_start_matches("./").trim_matches('/');
+            if pattern.is_empty() {
+                continue;
+            }
+            let glob = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()
+                .map_err(|e| eyre!("Invalid .dockerignore pattern `{line}`: {e}"))?;
+            patterns.push((glob.compile_matcher(), negate));
+        }
+        Ok(Self { patterns })
+    }
+
+    /// Reads `.dockerignore` in `context`, if there is one
+    pub fn load(context: &Path) -> Result<Self> {
+        match std::fs::read_to_string(context.join(".dockerignore")) {
+            Ok(dockerignore) => Self::parse(&dockerignore),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Self { patterns: Vec::new() }),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Whether `path`, relative to the context root, is excluded
+    pub fn is_excluded(&self, path: &Path) -> bool {
+        let mut excluded = false;
+        for (glob, negate) in &self.patterns {
+            if path.ancestors().any(|p| !p.as_os_str().is_empty() && glob.is_match(p)) {
+                excluded = !negate;
+            }
+        }
+        excluded
+    }
+}
+
+/// Packs `context` into a gzip tarball, leaving out anything excluded by its `.dockerignore`
+///
+/// `dockerfile` is added as [`CONTEXT_DOCKERFILE`], so it does not need to be in the context.
+pub fn pack_context(context: &Path, dockerfile: &Path) -> Result<tempfile::NamedTempFile> {
+    let ignore = DockerIgnore::load(context)?;
+    let tarball = tempfile::Builder::new().suffix(".tar.gz").tempfile()?;
+    let mut archive = tar::Builder::new(GzEncoder::new(tarball.reopen()?, Compression::default()));
+    archive.follow_symlinks(false);
+
+    for entry in walkdir::WalkDir::new(context).min_depth(1).sort_by_file_name() {
+        let entry = entry?;
+        let path = entry.path().strip_prefix(context)?;
+        if ignore.is_excluded(path) {
+            debug!("Leaving {} out of the context", path.display());
+            continue;
+        }
+        archive.append_path_with_name(entry.path(), path)?;
+    }
+    archive.append_path_with_name(dockerfile, CONTEXT_DOCKERFILE)?;
+    archive.into_inner()?.finish()?;
+
+    Ok(tarball)
+}
+
+/// Variables available in image tag templates
+#[derive(Clone, Debug, Serialize)]
+pub struct TagVars {
+    /// The image `version`, or the spec Version of the project
+    pub version: String,
+    /// `autogitdate`
+    pub date: String,
+    pub branch: String,
+    pub git: GitVars,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct GitVars {
+    /// `autogitcommit`
+    pub commit: String,
+    /// The first 8 characters of the commit
+    pub short: String,
+    /// `autogitversion`
+    pub version: String,
+    /// `autogitdate`
+    pub date: String,
+}
+
+/// Expands tag templates like `${git.short}`, replacing anything not allowed in a tag
+pub fn expand_tags(templates: &[String], vars: &TagVars) -> Result<Vec<String>> {
+    templates
+        .iter()
+        .map(|template| {
+            let tag = render_template(template, vars)
+                .map_err(|e| eyre!("Invalid tag template `{template}`: {e}"))?;
+            Ok(sanitize_tag(&tag))
+        })
+        .collect()
+}
+
+/// Tags are up to 128 characters of `[A-Za-z0-9_.-]`, not starting with `.` or `-`
+fn sanitize_tag(tag: &str) -> String {
+    let tag = tag
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || "_.-".contains(c) { c } else { '-' })
+        .collect::<String>();
+    tag.trim_start_matches(['.', '-']).chars().take(128).collect()
+}
+
+/// The tag suffix for a per-platform image, e.g. `arm64-v8` for `linux/arm64/v8`
+pub fn platform_suffix(platform: &str) -> String {
+    platform.trim_start_matches("linux/").replace('/', "-")
 }
 
 pub struct OCIBuilder {
@@ -24,58 +393,840 @@ pub struct OCIBuilder {
     tag: String,
     version: String,
     label: Vec<String>,
+    platforms: Vec<String>,
+    extra_tags: Vec<String>,
+    opts: BuildOpts,
+    compress: bool,
 }
 
 impl OCIBuilder {
     pub fn new(context: String, tag: String, version: String) -> Self {
-        Self { context, tag, version, label: Vec::new() }
+        Self {
+            context,
+            tag,
+            version,
+            label: Vec::new(),
+            platforms: Vec::new(),
+            extra_tags: Vec::new(),
+            opts: BuildOpts::default(),
+            compress: false,
+        }
     }
 
     pub fn add_label(&mut self, label: String) {
         self.label.push(label);
     }
 
-    // We use string here because we want to let people use stuff like git contexts
-    pub fn build(&self, dockerfile: String, backend: OCIBackend, latest: bool) {
-        let mut cmd = backend.command();
+    /// Builds for each of `platforms` (e.g. `linux/arm64`) and combines them into a manifest list
+    pub fn platforms(&mut self, platforms: Vec<String>) -> &mut Self {
+        self.platforms = platforms;
+        self
+    }
+
+    /// Tags the image with each of `tags` as well
+    pub fn extra_tags(&mut self, tags: Vec<String>) -> &mut Self {
+        self.extra_tags = tags;
+        self
+    }
 
-        let real_tag = &format!("{}:{}", &self.tag, self.version);
+    pub fn opts(&mut self, opts: BuildOpts) -> &mut Self {
+        self.opts = opts;
+        self
+    }
+
+    /// Sends the context to Docker as a gzip tarball, for remote daemons
+    pub fn compress(&mut self, compress: bool) -> &mut Self {
+        self.compress = compress;
+        self
+    }
 
-        cmd.arg("build")
-            .arg(&self.context)
-            .arg("-f")
-            .arg(&dockerfile)
-            .arg("-t")
-            .env("DOCKER_BUILDKIT", "1")
-            .arg(real_tag);
+    fn tags(&self, latest: bool) -> Vec<String> {
+        let mut tags = vec![format!("{}:{}", self.tag, self.version)];
 
         if latest {
-            cmd.arg("-t").arg(&format!("{}:latest", &self.tag));
+            tags.push(format!("{}:latest", self.tag));
+        }
+
+        for tag in &self.extra_tags {
+            let tag = format!("{}:{tag}", self.tag);
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+        tags
+    }
+
+    fn build_cmd(
+        &self,
+        dockerfile: &str,
+        backend: OCIBackend,
+        tags: &[String],
+        platform: Option<&str>,
+    ) -> Command {
+        let mut cmd = backend.command();
+
+        match (backend, platform) {
+            (OCIBackend::Docker, Some(_)) => {
+                cmd.arg("buildx").arg("build").arg("--load");
+            }
+            _ => {
+                cmd.arg(backend.build_command());
+            }
+        }
+
+        if let Some(platform) = platform {
+            cmd.arg("--platform").arg(platform);
+        }
+
+        let (dockerfile, context) =
+            if self.compress { (CONTEXT_DOCKERFILE, "-") } else { (dockerfile, &*self.context) };
+        cmd.arg("-f").arg(dockerfile);
+
+        for tag in tags {
+            cmd.arg("-t").arg(tag);
         }
 
         for label in &self.label {
             cmd.arg("--label").arg(label);
         }
+
+        self.opts.args(backend, &mut cmd);
+
+        match backend {
+            OCIBackend::Docker => {
+                cmd.env("DOCKER_BUILDKIT", "1");
+            }
+            OCIBackend::Buildah => {
+                cmd.arg("--layers");
+                // unprivileged runners usually cannot set up the namespaces for OCI isolation
+                if !nix::unistd::geteuid().is_root()
+                    && std::env::var_os("BUILDAH_ISOLATION").is_none()
+                {
+                    cmd.arg("--isolation=chroot");
+                }
+            }
+            OCIBackend::Podman => {}
+        }
+
+        // buildah wants the context last
+        cmd.arg(context);
+        cmd
+    }
+
+    /// Runs a build command, with the packed context on stdin if there is one
+    async fn run(mut cmd: Command, context: Option<&Path>) -> Result<()> {
+        if let Some(tarball) = context {
+            cmd.stdin(File::open(tarball)?);
+        }
+        cmd.log().await
     }
+
+    /// Commands rebuilding the `loaded` image with the labels, from a Dockerfile written to `dir`
+    ///
+    /// `FROM` cannot use a bare image ID, so those are tagged as the first tag beforehand.
+    fn relabel_cmds(
+        &self,
+        loaded: &str,
+        backend: OCIBackend,
+        tags: &[String],
+        dir: &Path,
+    ) -> Result<Vec<Command>> {
+        let mut cmds = Vec::new();
+        let base = if is_image_id(loaded) {
+            let mut cmd = backend.command();
+            cmd.arg("tag").arg(loaded).arg(&tags[0]);
+            cmds.push(cmd);
+            &tags[0]
+        } else {
+            loaded
+        };
+
+        let dockerfile = dir.join("Dockerfile");
+        std::fs::write(&dockerfile, format!("FROM {base}\n"))?;
+        let builder = OCIBuilder {
+            context: dir.display().to_string(),
+            tag: self.tag.clone(),
+            version: self.version.clone(),
+            label: self.label.clone(),
+            platforms: Vec::new(),
+            extra_tags: self.extra_tags.clone(),
+            opts: BuildOpts::default(),
+            compress: false,
+        };
+        cmds.push(builder.build_cmd(&dockerfile.display().to_string(), backend, tags, None));
+        Ok(cmds)
+    }
+
+    /// Loads a pre-built image from `archive` and tags it like a built image
+    ///
+    /// The image is only rebuilt if labels need to be added.
+    pub async fn import(
+        &self,
+        archive: &Path,
+        backend: OCIBackend,
+        latest: bool,
+    ) -> Result<Vec<String>> {
+        let loaded = load(backend, archive).await?;
+        let tags = self.tags(latest);
+
+        if self.label.is_empty() {
+            for tag in &tags {
+                backend.command().arg("tag").arg(&loaded).arg(tag).log().await?;
+            }
+            return Ok(tags);
+        }
+
+        let context = tempfile::tempdir()?;
+        for mut cmd in self.relabel_cmds(&loaded, backend, &tags, context.path())? {
+            cmd.log().await?;
+        }
+        Ok(tags)
+    }
+
+    /// Builds the image, returning every image reference created.
+    ///
+    /// For multi-platform builds, this is the manifest lists followed by the per-platform images,
+    /// tagged `<tag>:<version>-<platform>`.
+    // We use string here because we want to let people use stuff like git contexts
+    pub async fn build(
+        &self,
+        dockerfile: String,
+        backend: OCIBackend,
+        latest: bool,
+    ) -> Result<Vec<String>> {
+        let mut tags = self.tags(latest);
+
+        let tarball = if self.compress {
+            let context = PathBuf::from(&self.context);
+            if !context.is_dir() {
+                return Err(eyre!("Only local contexts can be compressed, not {}", self.context));
+            }
+            info!("Packing {}", context.display());
+            let file = PathBuf::from(&dockerfile);
+            Some(tokio::task::spawn_blocking(move || pack_context(&context, &file)).await??)
+        } else {
+            None
+        };
+        let context = tarball.as_ref().map(|t| t.path());
+
+        if self.platforms.is_empty() {
+            Self::run(self.build_cmd(&dockerfile, backend, &tags, None), context).await?;
+            return Ok(tags);
+        }
+
+        if matches!(backend, OCIBackend::Docker) && !docker_containerd_store().await? {
+            return Err(eyre!(
+                "Multi-platform Docker builds need the containerd image store, \
+                 see https://docs.docker.com/storage/containerd/"
+            ));
+        }
+
+        let mut images = Vec::new();
+        for platform in &self.platforms {
+            let image = format!("{}:{}-{}", self.tag, self.version, platform_suffix(platform));
+            let cmd =
+                self.build_cmd(&dockerfile, backend, std::slice::from_ref(&image), Some(platform));
+            Self::run(cmd, context).await?;
+            images.push(image);
+        }
+
+        match backend {
+            // buildx assembles the list itself, with every platform cached by the builds above
+            OCIBackend::Docker => {
+                let platforms = self.platforms.join(",");
+                let cmd = self.build_cmd(&dockerfile, backend, &tags, Some(&platforms));
+                Self::run(cmd, context).await?;
+            }
+            _ => {
+                for tag in &tags {
+                    create_manifest(backend, tag, &images).await?;
+                }
+            }
+        }
+
+        tags.extend(images);
+        Ok(tags)
+    }
+}
+
+/// Loads an image archive, returning a reference to the loaded image
+pub async fn load(backend: OCIBackend, archive: &Path) -> Result<String> {
+    info!("Loading {}", archive.display());
+    let mut cmd = backend.command();
+    match backend {
+        // buildah has no `load`, but can pull from an archive
+        OCIBackend::Buildah => {
+            let format = ArchiveFormat::detect(archive)?;
+            cmd.arg("pull").arg(format!("{}:{}", format.transport(), archive.display()));
+        }
+        _ => {
+            cmd.arg("load").arg("-i").arg(archive);
+        }
+    }
+
+    let output = cmd.output().await.map_err(|e| eyre!("Failed to run {backend:?}: {e}"))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to load {}: {}",
+            archive.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let loaded = match backend {
+        OCIBackend::Buildah => stdout.lines().last().map(|s| s.trim().to_string()),
+        _ => parse_loaded(&stdout),
+    };
+    loaded.ok_or_else(|| eyre!("Cannot find the image loaded from {}", archive.display()))
+}
+
+/// `Loaded image: <ref>`, `Loaded image(s): <ref>,...` or `Loaded image ID: <id>`
+fn parse_loaded(output: &str) -> Option<String> {
+    let (_, loaded) = output.lines().rev().find_map(|line| line.split_once(": "))?;
+    loaded.split(',').next().map(|s| s.trim().to_string())
+}
+
+/// Whether `image` is an image ID rather than a name, like `sha256:<digest>` or a bare digest
+fn is_image_id(image: &str) -> bool {
+    let digest = image.strip_prefix("sha256:").unwrap_or(image);
+    (image.len() != digest.len() || digest.len() == 64)
+        && digest.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Whether Docker can load multi-platform images
+///
+/// The classic image store cannot hold manifest lists, whatever the buildx driver.
+async fn docker_containerd_store() -> Result<bool> {
+    let output = OCIBackend::Docker
+        .command()
+        .args(["info", "--format", "{{json .DriverStatus}}"])
+        .output()
+        .await
+        .map_err(|e| eyre!("Failed to run Docker: {e}"))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to get the Docker storage driver: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(is_containerd_store(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Whether the `DriverStatus` from `docker info` is the containerd image store
+fn is_containerd_store(driver_status: &str) -> bool {
+    driver_status.contains("io.containerd.snapshotter")
+}
+
+/// Creates the manifest list `list` from local `images`
+async fn create_manifest(backend: OCIBackend, list: &str, images: &[String]) -> Result<()> {
+    // replace the list from a previous build, which may not exist
+    let _ = backend.command().args(["manifest", "rm", list]).output().await;
+
+    backend.command().args(["manifest", "create", list]).log().await?;
+    for image in images {
+        backend
+            .command()
+            .args(["manifest", "add", list])
+            .arg(format!("containers-storage:{image}"))
+            .log()
+            .await?;
+    }
+    Ok(())
+}
+
+/// The standard `org.opencontainers.image` labels, from the git repository in the working directory
+pub fn provenance_labels(version: &str) -> Vec<String> {
+    let created = get_commit_time_cwd()
+        .and_then(|time| Utc.timestamp_opt(time, 0).single())
+        .unwrap_or_else(Utc::now)
+        .to_rfc3339_opts(SecondsFormat::Secs, true);
+
+    let mut labels = vec![
+        format!("org.opencontainers.image.created={created}"),
+        format!("org.opencontainers.image.version={version}"),
+    ];
+    if let Some(commit) = get_commit_id_cwd() {
+        labels.push(format!("org.opencontainers.image.revision={commit}"));
+    }
+    if let Some(url) = get_remote_url_cwd() {
+        labels.push(format!("org.opencontainers.image.source={url}"));
+    }
+    labels
 }
 
-pub fn build_oci(
+/// Builds or imports `image`, returning every image reference created
+///
+/// `vars` are needed for images with tag templates.
+pub async fn build_oci(
     backend: OCIBackend,
-    dockerfile: String,
-    latest: bool,
     tag: String,
-    version: String,
-    context: String,
-) -> Vec<String> {
-    let mut builder = OCIBuilder::new(context, tag.clone(), version.clone());
-    builder.add_label(format!("com.fyralabs.anda.version={}", env!("CARGO_PKG_VERSION")));
+    image: DockerImage,
+    vars: Option<&TagVars>,
+    oci_opts: &OciOpts,
+    rpm_repo: &Path,
+) -> Result<Vec<String>> {
+    let latest = image.tag_latest.unwrap_or(false);
+    let mut builder = OCIBuilder::new(
+        image.context.clone(),
+        tag.clone(),
+        image.version.clone().unwrap_or_else(|| "latest".to_string()),
+    );
+    // labels from the manifest come last, so they can override these
+    if image.dockerfile.is_some() {
+        for label in provenance_labels(&builder.version) {
+            builder.add_label(label);
+        }
+    }
+    for (key, value) in &image.labels {
+        builder.add_label(format!("{key}={value}"));
+    }
 
-    builder.build(dockerfile, backend, latest);
+    if let Some(templates) = &image.tags {
+        let mut vars = vars.cloned().ok_or_else(|| eyre!("No tag variables for image {tag}"))?;
+        if let Some(version) = &image.version {
+            vars.version = version.clone();
+        }
+        builder.extra_tags(expand_tags(templates, &vars)?);
+    }
 
-    let mut tags = vec![format!("{tag}:{version}")];
+    match (&image.dockerfile, &image.import) {
+        (Some(dockerfile), None) => {
+            if builder.context.is_empty() {
+                return Err(eyre!("No context defined for image {tag}"));
+            }
+            builder.add_label(format!("com.fyralabs.anda.version={}", env!("CARGO_PKG_VERSION")));
+            builder.platforms(image.platforms.clone().unwrap_or_default());
+            let mut opts = BuildOpts::new(backend, &image, &oci_opts.build_arg)?;
+            if image.rpm_repo.unwrap_or(false) {
+                opts.rpm_repo(rpm_repo)?;
+            }
+            builder.opts(opts);
+            match backend {
+                OCIBackend::Docker => {
+                    builder.compress(oci_opts.compress);
+                }
+                _ if oci_opts.compress => {
+                    warn!("Only Docker takes a compressed context, sending {tag} as is");
+                }
+                _ => {}
+            }
+            builder.build(dockerfile.clone(), backend, latest).await
+        }
+        (None, Some(archive)) => builder.import(archive, backend, latest).await,
+        (Some(_), Some(_)) => {
+            Err(eyre!("Image {tag} can either be built from a dockerfile or imported, not both"))
+        }
+        (None, None) => Err(eyre!("No dockerfile or archive to import defined for image {tag}")),
+    }
+}
+
+/// Pushes `image` to its registry, returning the digest of what was pushed
+///
+/// Credentials are read from the usual places: `auth.json` for Podman and Buildah
+/// (falling back to the Docker config), and `~/.docker/config.json` for Docker.
+pub async fn push(backend: OCIBackend, image: &str) -> Result<String> {
+    info!("Pushing {image}");
+    match backend {
+        OCIBackend::Docker => {
+            let output = backend
+                .command()
+                .arg("push")
+                .arg(image)
+                .output()
+                .await
+                .map_err(|e| eyre!("Failed to run docker: {e}"))?;
+            if !output.status.success() {
+                return Err(eyre!(
+                    "Failed to push {image}: {}",
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ));
+            }
+            parse_docker_digest(&String::from_utf8_lossy(&output.stdout))
+                .ok_or_else(|| eyre!("Cannot find the digest of {image} in the docker push output"))
+        }
+        _ => {
+            let digestfile = tempfile::NamedTempFile::new()?;
+
+            let mut cmd = backend.command();
+            if is_manifest_list(backend, image).await {
+                cmd.args(["manifest", "push", "--all"]);
+            } else {
+                cmd.arg("push");
+            }
+            cmd.arg("--digestfile").arg(digestfile.path()).arg(image);
+            cmd.arg(format!("docker://{image}"));
+            cmd.log().await?;
+
+            Ok(std::fs::read_to_string(digestfile.path())?.trim().to_string())
+        }
+    }
+}
+
+/// Whether `image` is a manifest list in Podman or Buildah storage
+async fn is_manifest_list(backend: OCIBackend, image: &str) -> bool {
+    backend
+        .command()
+        .args(["manifest", "exists", image])
+        .output()
+        .await
+        .is_ok_and(|o| o.status.success())
+}
+
+/// A file name for things derived from `image`
+fn file_name(image: &str) -> String {
+    image.replace(['/', ':'], "_")
+}
+
+/// Generates an SBOM for `image` with syft, returning the path of the SBOM in `dir`
+///
+/// `format` is any syft output format, e.g. `spdx-json` or `cyclonedx-json`.
+pub async fn sbom(backend: OCIBackend, image: &str, format: &str, dir: &Path) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.{format}", file_name(image)));
+    info!("Generating SBOM for {image}");
+
+    // syft cannot read buildah's storage directly, so it gets an archive instead
+    let scratch = tempfile::tempdir()?;
+    let source = match backend {
+        OCIBackend::Docker => format!("docker:{image}"),
+        OCIBackend::Podman => format!("podman:{image}"),
+        OCIBackend::Buildah => {
+            let archive = export(backend, image, ArchiveFormat::Oci, scratch.path()).await?;
+            format!("oci-archive:{}", archive.display())
+        }
+    };
+
+    Command::new("syft")
+        .arg("scan")
+        .arg(source)
+        .arg("-o")
+        .arg(format!("{format}={}", path.display()))
+        .log()
+        .await?;
+    Ok(path)
+}
+
+/// Saves `image` as an archive in `dir`, returning the path of the archive
+///
+/// Manifest lists are exported with all their images.
+pub async fn export(
+    backend: OCIBackend,
+    image: &str,
+    format: ArchiveFormat,
+    dir: &Path,
+) -> Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.tar", file_name(image)));
+    if path.exists() {
+        std::fs::remove_file(&path)?;
+    }
+    info!("Exporting {image} to {}", path.display());
+
+    let mut cmd = backend.command();
+    match backend {
+        OCIBackend::Docker => {
+            if format != ArchiveFormat::Docker {
+                return Err(eyre!("Docker can only export images as docker-archive"));
+            }
+            cmd.arg("save").arg("-o").arg(&path).arg(image);
+        }
+        _ => {
+            if is_manifest_list(backend, image).await {
+                cmd.args(["manifest", "push", "--all"]);
+            } else {
+                cmd.arg("push");
+            }
+            cmd.arg(image).arg(format!("{}:{}:{image}", format.transport(), path.display()));
+        }
+    }
+    cmd.log().await?;
+
+    Ok(path)
+}
+
+/// `docker push` ends with `<tag>: digest: sha256:<digest> size: <size>`
+fn parse_docker_digest(output: &str) -> Option<String> {
+    let (_, rest) = output.lines().rev().find_map(|line| line.split_once("digest: "))?;
+    rest.split_whitespace().next().map(|s| s.to_string())
+}
+
+/// Pins `image` to `digest`, e.g. `ghcr.io/terrapkg/base@sha256:...`
+pub fn pin(image: &str, digest: &str) -> String {
+    let repo = match image.rsplit_once(':') {
+        // a colon before the last slash is a registry port
+        Some((repo, tag)) if !tag.contains('/') => repo,
+        _ => image,
+    };
+    format!("{repo}@{digest}")
+}
+
+/// Pushes every OCI image in `artifacts`, recording their pinned digests
+pub async fn push_artifacts(artifacts: &mut Artifacts) -> Result<usize> {
+    let images = artifacts
+        .packages
+        .iter()
+        .filter_map(|(image, kind)| Some((image.clone(), OCIBackend::for_package(*kind)?)))
+        .collect::<Vec<_>>();
+
+    for (image, backend) in &images {
+        let digest = push(*backend, image).await?;
+        artifacts.add_digest(image.clone(), pin(image, &digest));
+    }
+    Ok(images.len())
+}
+
+#[cfg(test)]
+mod test_oci {
+    use super::*;
+
+    #[test]
+    fn test_platform_build() {
+        assert_eq!(platform_suffix("linux/amd64"), "amd64");
+        assert_eq!(platform_suffix("linux/arm64/v8"), "arm64-v8");
+
+        let builder = OCIBuilder::new(".".to_string(), "anda".to_string(), "1".to_string());
+        let cmd = builder.build_cmd(
+            "Dockerfile",
+            OCIBackend::Docker,
+            &["anda:1-arm64".to_string()],
+            Some("linux/arm64"),
+        );
+        let args = cmd.as_std().get_args().collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            [
+                "buildx",
+                "build",
+                "--load",
+                "--platform",
+                "linux/arm64",
+                "-f",
+                "Dockerfile",
+                "-t",
+                "anda:1-arm64",
+                "."
+            ]
+        );
+    }
+
+    #[test]
+    fn test_containerd_store() {
+        assert!(is_containerd_store(r#"[["driver-type","io.containerd.snapshotter.v1"]]"#));
+        assert!(!is_containerd_store(
+            r#"[["Backing Filesystem","extfs"],["Supports d_type","true"]]"#
+        ));
+    }
+
+    #[test]
+    fn test_archive_format() {
+        assert_eq!("oci-archive".parse::<ArchiveFormat>().unwrap(), ArchiveFormat::Oci);
+        assert_eq!(
+            "docker-archive".parse::<ArchiveFormat>().unwrap().transport(),
+            "docker-archive"
+        );
+        assert!("tar".parse::<ArchiveFormat>().is_err());
+    }
+
+    #[test]
+    fn test_import_labels() {
+        let mut builder = OCIBuilder::new(String::new(), "anda".to_string(), "1".to_string());
+        builder.add_label("org.opencontainers.image.title=anda image".to_string());
+        let tags = builder.tags(true);
+        let dir = tempfile::tempdir().unwrap();
+        let dockerfile = dir.path().join("Dockerfile");
+        let args =
+            |cmd: &Command| cmd.as_std().get_args().map(|a| a.to_owned()).collect::<Vec<_>>();
+
+        // a named image is used as the base directly
+        let cmds = builder
+            .relabel_cmds("localhost/anda:1", OCIBackend::Podman, &tags, dir.path())
+            .unwrap();
+        assert_eq!(cmds.len(), 1);
+        assert_eq!(std::fs::read_to_string(&dockerfile).unwrap(), "FROM localhost/anda:1\n");
+        let build = args(&cmds[0]);
+        assert!(build
+            .windows(2)
+            .any(|a| a == ["--label", "org.opencontainers.image.title=anda image"]));
+        assert!(build.windows(2).any(|a| a == ["-f".as_ref(), dockerfile.as_os_str()]));
+
+        // an image ID is tagged first, so the rebuild is still based on the loaded image
+        let cmds =
+            builder.relabel_cmds("sha256:0123abcd", OCIBackend::Docker, &tags, dir.path()).unwrap();
+        assert_eq!(args(&cmds[0]), ["tag", "sha256:0123abcd", "anda:1"]);
+        assert_eq!(std::fs::read_to_string(&dockerfile).unwrap(), "FROM anda:1\n");
+        assert!(args(&cmds[1]).windows(2).any(|a| a == ["-t", "anda:latest"]));
+
+        assert!(is_image_id(&"0123abcd".repeat(8)));
+        assert!(!is_image_id("anda"));
+    }
+
+    #[test]
+    fn test_load() {
+        assert_eq!(parse_loaded("Loaded image: localhost/anda:1\n").unwrap(), "localhost/anda:1");
+        assert_eq!(parse_loaded("Loaded image ID: sha256:0123abcd\n").unwrap(), "sha256:0123abcd");
+        assert_eq!(
+            parse_loaded("Getting image source signatures\nLoaded image(s): anda:1,anda:latest\n")
+                .unwrap(),
+            "anda:1"
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("image.tar");
+        let mut archive = tar::Builder::new(File::create(&path).unwrap());
+        let layout = br#"{"imageLayoutVersion": "1.0.0"}"#;
+        let mut header = tar::Header::new_gnu();
+        header.set_size(layout.len() as u64);
+        header.set_mode(0o644);
+        archive.append_data(&mut header, "oci-layout", &layout[..]).unwrap();
+        archive.finish().unwrap();
+        assert_eq!(ArchiveFormat::detect(&path).unwrap(), ArchiveFormat::Oci);
+    }
+
+    #[test]
+    fn test_tag_templates() {
+        let vars = TagVars {
+            version: "0.1.7".to_string(),
+            date: "20221020".to_string(),
+            branch: "feature/oci".to_string(),
+            git: GitVars {
+                commit: "b904205c1f24ba".to_string(),
+                short: "b904205c".to_string(),
+                version: "20221020.b904205c".to_string(),
+                date: "20221020".to_string(),
+            },
+        };
+        let templates = ["${version}", "${git.short}", "${branch}-${date}"].map(String::from);
+        assert_eq!(
+            expand_tags(&templates, &vars).unwrap(),
+            ["0.1.7", "b904205c", "feature-oci-20221020"]
+        );
+
+        let mut builder = OCIBuilder::new(".".to_string(), "anda".to_string(), "1".to_string());
+        builder.extra_tags(vec!["1".to_string(), "b904205c".to_string()]);
+        assert_eq!(builder.tags(true), ["anda:1", "anda:latest", "anda:b904205c"]);
+    }
+
+    #[test]
+    fn test_build_opts() {
+        let image = DockerImage {
+            build_args: [("VERSION".to_string(), "1".to_string())].into(),
+            target: Some("runtime".to_string()),
+            secret: [(
+                "token".to_string(),
+                anda_config::OciSecret { file: None, env: Some("GITHUB_TOKEN".to_string()) },
+            )]
+            .into(),
+            network: Some("none".to_string()),
+            pull: Some("never".to_string()),
+            ..Default::default()
+        };
+        let cli_args = ["VERSION=2".to_string(), "HTTP_PROXY".to_string()];
+        let opts = BuildOpts::new(OCIBackend::Podman, &image, &cli_args).unwrap();
+        assert_eq!(opts.build_args, ["VERSION=2", "HTTP_PROXY"]);
+
+        let mut cmd = Command::new("podman");
+        opts.args(OCIBackend::Podman, &mut cmd);
+        let args = cmd.as_std().get_args().collect::<Vec<_>>();
+        assert_eq!(
+            args,
+            [
+                "--build-arg",
+                "VERSION=2",
+                "--build-arg",
+                "HTTP_PROXY",
+                "--target",
+                "runtime",
+                "--secret",
+                "id=token,env=GITHUB_TOKEN",
+                "--network",
+                "none",
+                "--pull=never"
+            ]
+        );
+
+        assert!(BuildOpts::new(OCIBackend::Docker, &image, &[]).is_err());
+
+        let repo = tempfile::tempdir().unwrap();
+        let mut opts = BuildOpts::default();
+        assert!(opts.rpm_repo(repo.path()).is_err());
+        std::fs::create_dir_all(repo.path().join("repodata")).unwrap();
+        opts.rpm_repo(repo.path()).unwrap();
+        let root = repo.path().canonicalize().unwrap();
+        let repo_file = std::fs::read_to_string(root.join(CONTAINER_REPO_FILE)).unwrap();
+        assert!(repo_file.contains("baseurl=file:///run/anda/rpm\n"));
+
+        let mut cmd = Command::new("buildah");
+        opts.args(OCIBackend::Buildah, &mut cmd);
+        let args = cmd.as_std().get_args().collect::<Vec<_>>();
+        assert_eq!(args[1].to_string_lossy(), format!("{}:/run/anda/rpm:ro,z", root.display()));
+
+        // Docker only gets the repository as a build context, the Dockerfile has to mount it
+        let mut cmd = Command::new("docker");
+        opts.args(OCIBackend::Docker, &mut cmd);
+        let args = cmd.as_std().get_args().map(|a| a.to_string_lossy()).collect::<Vec<_>>();
+        assert_eq!(args, ["--build-context".to_string(), format!("anda-rpm={}", root.display())]);
+    }
+
+    #[test]
+    fn test_pack_context() {
+        let context = tempfile::tempdir().unwrap();
+        let root = context.path();
+        std::fs::write(root.join(".dockerignore"), "# build output\ntarget\n*.md\n!README.md\n")
+            .unwrap();
+        std::fs::create_dir_all(root.join("target/debug")).unwrap();
+        std::fs::create_dir_all(root.join("docs")).unwrap();
+        for file in ["target/debug/anda", "CHANGES.md", "README.md", "docs/index.md", "Dockerfile"]
+        {
+            std::fs::write(root.join(file), file).unwrap();
+        }
+
+        let ignore = DockerIgnore::load(root).unwrap();
+        assert!(ignore.is_excluded(Path::new("target/debug/anda")));
+        assert!(ignore.is_excluded(Path::new("CHANGES.md")));
+        assert!(!ignore.is_excluded(Path::new("README.md")));
+        // `*` only matches in the context root
+        assert!(!ignore.is_excluded(Path::new("docs/index.md")));
+
+        let tarball = pack_context(root, &root.join("Dockerfile")).unwrap();
+        let mut archive =
+            tar::Archive::new(flate2::read::GzDecoder::new(File::open(tarball.path()).unwrap()));
+        let mut paths = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect::<Vec<_>>();
+        paths.sort();
+        assert_eq!(
+            paths,
+            [
+                ".anda.Dockerfile",
+                ".dockerignore",
+                "Dockerfile",
+                "README.md",
+                "docs",
+                "docs/index.md"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_provenance_labels() {
+        let labels = provenance_labels("0.1.7");
+        assert!(labels[0].starts_with("org.opencontainers.image.created="));
+        assert_eq!(labels[1], "org.opencontainers.image.version=0.1.7");
+    }
 
-    if latest {
-        tags.push(format!("{tag}:latest"));
+    #[test]
+    fn test_push_digest() {
+        let output = "The push refers to repository [localhost:5000/anda]\n\
+                      5f70bf18a086: Pushed\n\
+                      1: digest: sha256:4c8e1d8a9b0f size: 528\n";
+        let digest = parse_docker_digest(output).unwrap();
+        assert_eq!(digest, "sha256:4c8e1d8a9b0f");
+        assert_eq!(
+            pin("localhost:5000/anda:1", &digest),
+            "localhost:5000/anda@sha256:4c8e1d8a9b0f"
+        );
+        assert_eq!(pin("localhost:5000/anda", &digest), "localhost:5000/anda@sha256:4c8e1d8a9b0f");
     }
-    tags
 }
@@ -0,0 +1,379 @@
+//! Publishing built RPMs to a repository, and OCI images to their registries
+//!
+//! RPMs recorded in the build reports are copied to the destination laid out as:
+//! - `<distro>/<arch>/`: binary RPMs, with noarch packages copied into every arch
+//! - `<distro>/<arch>/debug/`: debuginfo and debugsource RPMs
+//! - `<distro>/source/`: source RPMs
+//!
+//! Each of these is its own repository. The metadata is generated next to the old one
+//! and swapped in at the end, so clients never see a half-written repository.
+//!
+//! rsync destinations are mirrored into `<target_dir>/publish` first, published there,
+//! then pushed back with the packages first and the metadata last.
+//!
+//! OCI images are pushed with the backend that built them, and the reports
+//! are updated with the digests that were pushed.
+
+use crate::{
+    artifacts::Artifacts, cli::PackageType, oci::push_artifacts, repo::RpmName, sign::SignOpts,
+    util::CommandLog,
+};
+use anda_config::PublishConfig;
+use color_eyre::{eyre::eyre, Result};
+use nix::fcntl::{renameat2, RenameFlags};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+const DEBUG_EXCLUDES: [&str; 2] = ["*-debuginfo-*", "*-debugsource-*"];
+
+/// Where to publish to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    Local(PathBuf),
+    /// `host:path` or `rsync://host/path`
+    Rsync(String),
+}
+
+impl Destination {
+    pub fn parse(dest: &str) -> Self {
+        let remote = dest.starts_with("rsync://")
+            || dest
+                .split_once(':')
+                .is_some_and(|(host, _)| !host.is_empty() && !host.contains('/'));
+        if remote {
+            Destination::Rsync(dest.trim_end_matches('/').to_string())
+        } else {
+            Destination::Local(PathBuf::from(dest))
+        }
+    }
+}
+
+/// The distro part of the dist tag in a release, e.g. `fc37` in `1.fc37`
+pub fn dist_tag(release: &str) -> Option<String> {
+    release
+        .rsplit('.')
+        .find(|part| {
+            let digits = part.trim_start_matches(|c: char| c.is_ascii_alphabetic());
+            digits.len() < part.len()
+                && !digits.is_empty()
+                && digits.chars().all(|c| c.is_ascii_digit())
+        })
+        .map(|s| s.to_string())
+}
+
+/// Copies `rpms` into `tree`, returning the repository directories that need new metadata
+pub fn stage(rpms: &[PathBuf], tree: &Path, distro: Option<&str>) -> Result<BTreeSet<PathBuf>> {
+    let mut changed = BTreeSet::new();
+    let mut noarch = Vec::new();
+
+    for rpm in rpms {
+        let filename = rpm.file_name().unwrap_or_default().to_string_lossy().to_string();
+        let name = RpmName::parse(&filename)
+            .ok_or_else(|| eyre!("Cannot parse RPM file name {filename}"))?;
+        let distro = match distro {
+            Some(distro) => distro.to_string(),
+            None => dist_tag(&name.release).ok_or_else(|| {
+                eyre!("Cannot detect the distro of {filename}, please set it with --distro")
+            })?,
+        };
+
+        let dir = match name.arch.as_str() {
+            "src" => tree.join(&distro).join("source"),
+            "noarch" => {
+                noarch.push((rpm, distro));
+                continue;
+            }
+            arch if name.is_debug() => tree.join(&distro).join(arch).join("debug"),
+            arch => tree.join(&distro).join(arch),
+        };
+        copy(rpm, &dir)?;
+        changed.insert(dir);
+    }
+
+    for (rpm, distro) in noarch {
+        for dir in arch_dirs(&tree.join(&distro))? {
+            copy(rpm, &dir)?;
+            changed.insert(dir);
+        }
+    }
+
+    Ok(changed)
+}
+
+/// The binary arch repositories in `distro_dir`, or `noarch` if there are none yet
+fn arch_dirs(distro_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut dirs = Vec::new();
+    if distro_dir.exists() {
+        for entry in std::fs::read_dir(distro_dir)? {
+            let path = entry?.path();
+            let name = path.file_name().unwrap_or_default();
+            if path.is_dir() && name != "source" && name != "noarch" {
+                dirs.push(path);
+            }
+        }
+    }
+    if dirs.is_empty() {
+        dirs.push(distro_dir.join("noarch"));
+    }
+    Ok(dirs)
+}
+
+fn copy(rpm: &Path, dir: &Path) -> Result<()> {
+    std::fs::create_dir_all(dir)?;
+    let dest = dir.join(rpm.file_name().unwrap_or_default());
+    debug!("Copying {} to {}", rpm.display(), dest.display());
+    std::fs::copy(rpm, dest)?;
+    Ok(())
+}
+
+/// Generates new metadata for the repository in `dir` and swaps it in
+pub async fn update_metadata(dir: &Path, sign: Option<&SignOpts>) -> Result<()> {
+    let staging = dir.join(".anda-staging");
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::create_dir_all(&staging)?;
+
+    let mut cmd = Command::new("createrepo_c");
+    cmd.arg("--quiet").arg("--outputdir").arg(&staging);
+    if dir.join("repodata").exists() {
+        cmd.arg("--update").arg("--update-md-path").arg(dir);
+    }
+    if !dir.ends_with("debug") {
+        for exclude in DEBUG_EXCLUDES {
+            cmd.arg("--excludes").arg(exclude);
+        }
+    }
+    cmd.arg(dir);
+    cmd.log().await?;
+
+    if let Some(sign) = sign {
+        sign.sign_repo(&staging).await?;
+    }
+
+    swap_repodata(dir, &staging.join("repodata"))?;
+    std::fs::remove_dir_all(&staging)?;
+    Ok(())
+}
+
+/// Replaces `dir/repodata` with `new`, atomically if the filesystem supports it
+fn swap_repodata(dir: &Path, new: &Path) -> Result<()> {
+    let repodata = dir.join("repodata");
+    if !repodata.exists() {
+        std::fs::rename(new, &repodata)?;
+        return Ok(());
+    }
+
+    // the old metadata ends up in `new`, which is cleaned up with the staging directory
+    if let Err(e) = renameat2(None, new, None, &repodata, RenameFlags::RENAME_EXCHANGE) {
+        warn!("Cannot swap {} atomically ({e}), replacing it instead", repodata.display());
+        let old = dir.join("repodata.old");
+        if old.exists() {
+            std::fs::remove_dir_all(&old)?;
+        }
+        std::fs::rename(&repodata, &old)?;
+        std::fs::rename(new, &repodata)?;
+        std::fs::remove_dir_all(old)?;
+    }
+    Ok(())
+}
+
+/// Copies `rpms` into the repository tree at `tree` and updates its metadata
+pub async fn publish_local(
+    rpms: &[PathBuf],
+    tree: &Path,
+    distro: Option<&str>,
+    sign: Option<&SignOpts>,
+) -> Result<()> {
+    for dir in stage(rpms, tree, distro)? {
+        info!("Updating repository metadata in {}", dir.display());
+        update_metadata(&dir, sign).await?;
+    }
+    Ok(())
+}
+
+async fn rsync(src: &str, dest: &str, args: &[&str]) -> Result<()> {
+    let mut cmd = Command::new("rsync");
+    cmd.arg("-a").args(args).arg(format!("{src}/")).arg(format!("{dest}/"));
+    cmd.log().await
+}
+
+/// `projects`, or all projects with a build report
+///
+/// Projects from nested manifests are named `<dir>/<name>`,
+/// so their reports are in subdirectories of `reports/`.
+fn reported_projects(target_dir: &Path, projects: &[String]) -> Result<Vec<String>> {
+    if !projects.is_empty() {
+        return Ok(projects.to_vec());
+    }
+
+    let reports = target_dir.join("reports");
+    if !reports.is_dir() {
+        return Err(eyre!("No build reports in {}", reports.display()));
+    }
+
+    let mut projects = Vec::new();
+    for entry in walkdir::WalkDir::new(&reports).sort_by_file_name() {
+        let path = entry?.into_path();
+        if path.is_file() && path.extension().is_some_and(|e| e == "json") {
+            let name = path.strip_prefix(&reports)?.with_extension("");
+            projects.push(name.to_string_lossy().to_string());
+        }
+    }
+    Ok(projects)
+}
+
+/// RPMs recorded in the build reports of `projects`
+fn reported_rpms(target_dir: &Path, projects: &[String]) -> Result<Vec<PathBuf>> {
+    let mut rpms = Vec::new();
+    for project in projects {
+        let report = Artifacts::load(target_dir, project)?;
+        for (path, kind) in report.packages {
+            if matches!(kind, PackageType::Rpm) && path.ends_with(".rpm") {
+                rpms.push(PathBuf::from(path));
+            }
+        }
+    }
+    Ok(rpms)
+}
+
+/// Pushes the OCI images recorded in the build reports of `projects`
+async fn push_images(target_dir: &Path, projects: &[String]) -> Result<usize> {
+    let mut pushed = 0;
+    for project in projects {
+        let mut report = Artifacts::load(target_dir, project)?;
+        let count = push_artifacts(&mut report).await?;
+        if count > 0 {
+            report.save(target_dir, project)?;
+            pushed += count;
+        }
+    }
+    Ok(pushed)
+}
+
+/// Entrypoint for `anda publish`
+pub async fn publish(
+    target_dir: &Path,
+    projects: &[String],
+    config: Option<&PublishConfig>,
+    destination: Option<String>,
+    distro: Option<String>,
+    sign: Option<SignOpts>,
+) -> Result<()> {
+    let projects = reported_projects(target_dir, projects)?;
+    let rpms = reported_rpms(target_dir, &projects)?;
+
+    // the RPM destination is checked before anything is pushed
+    let destination = destination.or_else(|| config.and_then(|c| c.destination.clone()));
+    if !rpms.is_empty() && destination.is_none() {
+        return Err(eyre!("No --destination specified, and no `destination` set in the manifest"));
+    }
+    let distro = distro.or_else(|| config.and_then(|c| c.distro.clone()));
+
+    let images = push_images(target_dir, &projects).await?;
+    if images > 0 {
+        println!("Pushed {images} OCI image(s)");
+    }
+
+    let Some(destination) = destination.filter(|_| !rpms.is_empty()) else {
+        if images == 0 {
+            return Err(eyre!("No RPMs or OCI images to publish"));
+        }
+        return Ok(());
+    };
+
+    match Destination::parse(&destination) {
+        Destination::Local(tree) => {
+            publish_local(&rpms, &tree, distro.as_deref(), sign.as_ref()).await?;
+        }
+        Destination::Rsync(remote) => {
+            let mirror = target_dir.join("publish");
+            std::fs::create_dir_all(&mirror)?;
+            let local = mirror.to_string_lossy();
+
+            info!("Mirroring {remote}");
+            rsync(&remote, &local, &["--delete"]).await?;
+            publish_local(&rpms, &mirror, distro.as_deref(), sign.as_ref()).await?;
+
+            info!("Pushing to {remote}");
+            rsync(&local, &remote, &["--exclude", "repodata/"]).await?;
+            rsync(&local, &remote, &["--delete-after", "--delay-updates"]).await?;
+        }
+    }
+
+    println!("Published {} RPM(s) to {destination}", rpms.len());
+    Ok(())
+}
+
+#[cfg(test)]
+mod test_publish {
+    use super::*;
+
+    #[test]
+    fn test_destination() {
+        assert_eq!(Destination::parse("/srv/repo"), Destination::Local("/srv/repo".into()));
+        assert_eq!(Destination::parse("./out:1"), Destination::Local("./out:1".into()));
+        assert_eq!(
+            Destination::parse("repo@example.com:/srv/repo/"),
+            Destination::Rsync("repo@example.com:/srv/repo".into())
+        );
+        assert_eq!(dist_tag("1.fc37"), Some("fc37".to_string()));
+        assert_eq!(dist_tag("0.1.rc1.um37"), Some("um37".to_string()));
+        assert_eq!(dist_tag("1"), None);
+    }
+
+    #[test]
+    fn test_stage() {
+        let build = tempfile::tempdir().unwrap();
+        let tree = tempfile::tempdir().unwrap();
+        let rpms = [
+            "umpkg-0.3.10-1.fc37.src.rpm",
+            "umpkg-0.3.10-1.fc37.x86_64.rpm",
+            "umpkg-debuginfo-0.3.10-1.fc37.x86_64.rpm",
+            "umpkg-doc-0.3.10-1.fc37.noarch.rpm",
+        ]
+        .map(|rpm| {
+            let path = build.path().join(rpm);
+            std::fs::write(&path, "").unwrap();
+            path
+        });
+
+        let changed = stage(&rpms, tree.path(), None).unwrap();
+        let fc37 = tree.path().join("fc37");
+        assert_eq!(
+            changed.into_iter().collect::<Vec<_>>(),
+            vec![fc37.join("source"), fc37.join("x86_64"), fc37.join("x86_64/debug")]
+        );
+        assert!(fc37.join("x86_64/umpkg-doc-0.3.10-1.fc37.noarch.rpm").exists());
+        assert!(fc37.join("x86_64/debug/umpkg-debuginfo-0.3.10-1.fc37.x86_64.rpm").exists());
+    }
+
+    #[test]
+    fn test_reported_projects() {
+        let target = tempfile::tempdir().unwrap();
+        assert!(reported_projects(target.path(), &[]).is_err());
+
+        for project in ["anda", "tests/umpkg"] {
+            Artifacts::new().save(target.path(), project).unwrap();
+        }
+        assert_eq!(reported_projects(target.path(), &[]).unwrap(), ["anda", "tests/umpkg"]);
+        assert_eq!(reported_projects(target.path(), &["anda".to_string()]).unwrap(), ["anda"]);
+    }
+
+    #[test]
+    fn test_swap_repodata() {
+        let dir = tempfile::tempdir().unwrap();
+        let new = dir.path().join(".anda-staging/repodata");
+        std::fs::create_dir_all(dir.path().join("repodata")).unwrap();
+        std::fs::create_dir_all(&new).unwrap();
+        std::fs::write(new.join("repomd.xml"), "new").unwrap();
+
+        swap_repodata(dir.path(), &new).unwrap();
+        let repomd = std::fs::read_to_string(dir.path().join("repodata/repomd.xml")).unwrap();
+        assert_eq!(repomd, "new");
+    }
+}
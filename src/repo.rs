@@ -0,0 +1,377 @@
+//! RPM repository management for `<target_dir>/rpm`
+//!
+//! The repository is laid out as:
+//! - `rpms/`: binary RPMs, served by the main repository at the root
+//! - `srpm/`: source RPMs, with their own repository metadata
+//! - `debug/`: debuginfo and debugsource RPMs, with their own repository metadata
+//! - `<name>.repo`: a dnf repo file pointing at all three
+
+use crate::{cli::RepoCommand, sign::SignOpts, util::CommandLog};
+use color_eyre::{eyre::eyre, Result};
+use std::{
+    cmp::Ordering,
+    collections::BTreeMap,
+    path::{Path, PathBuf},
+};
+use tokio::process::Command;
+use tracing::{debug, info};
+
+/// An RPM file name split into its parts
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RpmName {
+    pub name: String,
+    pub version: String,
+    pub release: String,
+    pub arch: String,
+}
+
+impl RpmName {
+    /// Parses `name-version-release.arch.rpm`
+    pub fn parse(filename: &str) -> Option<Self> {
+        let nvra = filename.strip_suffix(".rpm")?;
+        let (nvr, arch) = nvra.rsplit_once('.')?;
+        let (nv, release) = nvr.rsplit_once('-')?;
+        let (name, version) = nv.rsplit_once('-')?;
+        Some(Self {
+            name: name.to_string(),
+            version: version.to_string(),
+            release: release.to_string(),
+            arch: arch.to_string(),
+        })
+    }
+
+    pub fn is_debug(&self) -> bool {
+        self.name.ends_with("-debuginfo") || self.name.ends_with("-debugsource")
+    }
+
+    /// Compares version and release the way RPM does
+    pub fn cmp_evr(&self, other: &Self) -> Ordering {
+        rpmvercmp(&self.version, &other.version)
+            .then_with(|| rpmvercmp(&self.release, &other.release))
+    }
+}
+
+/// RPM's version comparison algorithm (`rpmvercmp`)
+pub fn rpmvercmp(a: &str, b: &str) -> Ordering {
+    if a == b {
+        return Ordering::Equal;
+    }
+
+    let sep = |c: char| !c.is_ascii_alphanumeric() && c != '~' && c != '^';
+    let (mut a, mut b) = (a, b);
+
+    loop {
+        a = a.trim_start_matches(sep);
+        b = b.trim_start_matches(sep);
+
+        // tilde sorts before everything, even the end of the version
+        match (a.starts_with('~'), b.starts_with('~')) {
+            (true, true) => {
+                a = &a[1..];
+                b = &b[1..];
+                continue;
+            }
+            (true, false) => return Ordering::Less,
+            (false, true) => return Ordering::Greater,
+            _ => {}
+        }
+
+        // caret sorts after the end of the version, but before anything else
+        match (a.starts_with('^'), b.starts_with('^')) {
+            (true, true) => {
+                a = &a[1..];
+                b = &b[1..];
+                continue;
+            }
+            (true, false) => return if b.is_empty() { Ordering::Greater } else { Ordering::Less },
+            (false, true) => return if a.is_empty() { Ordering::Less } else { Ordering::Greater },
+            _ => {}
+        }
+
+        if a.is_empty() || b.is_empty() {
+            break;
+        }
+
+        let numeric = a.starts_with(|c: char| c.is_ascii_digit());
+        let end = |s: &str| {
+            s.find(|c: char| if numeric { !c.is_ascii_digit() } else { !c.is_ascii_alphabetic() })
+                .unwrap_or(s.len())
+        };
+        let (seg_a, rest_a) = a.split_at(end(a));
+        let (seg_b, rest_b) = b.split_at(end(b));
+
+        // numeric segments are newer than alpha ones
+        if seg_b.is_empty() {
+            return if numeric { Ordering::Greater } else { Ordering::Less };
+        }
+
+        let ord = if numeric {
+            let seg_a = seg_a.trim_start_matches('0');
+            let seg_b = seg_b.trim_start_matches('0');
+            seg_a.len().cmp(&seg_b.len()).then_with(|| seg_a.cmp(seg_b))
+        } else {
+            seg_a.cmp(seg_b)
+        };
+        if ord != Ordering::Equal {
+            return ord;
+        }
+
+        a = rest_a;
+        b = rest_b;
+    }
+
+    a.len().cmp(&b.len())
+}
+
+/// The local RPM repository in `<target_dir>/rpm`
+#[derive(Clone, Debug)]
+pub struct RpmRepo {
+    root: PathBuf,
+    /// Name of the repository in the `.repo` file
+    name: String,
+    /// Base URL for the `.repo` file, defaults to a `file://` URL of `root`
+    baseurl: Option<String>,
+    /// Number of versions to keep for each package
+    keep: Option<usize>,
+}
+
+impl RpmRepo {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root, name: "anda".to_string(), baseurl: None, keep: None }
+    }
+
+    pub fn from_config(root: PathBuf, config: Option<&anda_config::RepoConfig>) -> Self {
+        let mut repo = Self::new(root);
+        if let Some(config) = config {
+            if let Some(name) = &config.name {
+                repo.name = name.clone();
+            }
+            repo.baseurl = config.baseurl.clone();
+            repo.keep = config.keep;
+        }
+        repo
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Whether the main repository has metadata yet
+    pub fn exists(&self) -> bool {
+        self.root.join("repodata").exists()
+    }
+
+    fn srpm_dir(&self) -> PathBuf {
+        self.root.join("srpm")
+    }
+
+    fn debug_dir(&self) -> PathBuf {
+        self.root.join("debug")
+    }
+
+    /// Moves newly built RPMs into their subrepositories, returning their new paths
+    pub fn add(&self, rpms: Vec<PathBuf>) -> Result<Vec<PathBuf>> {
+        let mut added = Vec::new();
+        for rpm in rpms {
+            let filename = rpm.file_name().unwrap_or_default().to_string_lossy().to_string();
+            let is_debug = RpmName::parse(&filename).is_some_and(|n| n.is_debug());
+            if is_debug && !rpm.starts_with(self.debug_dir()) {
+                std::fs::create_dir_all(self.debug_dir())?;
+                let dest = self.debug_dir().join(&filename);
+                debug!("Moving {} to {}", rpm.display(), dest.display());
+                std::fs::rename(&rpm, &dest)?;
+                added.push(dest);
+            } else {
+                added.push(rpm);
+            }
+        }
+        Ok(added)
+    }
+
+    /// Moves misplaced debuginfo RPMs out of `rpms/` into `debug/`
+    fn reorganize(&self) -> Result<()> {
+        let rpms = rpm_files(&self.root.join("rpms"));
+        self.add(rpms)?;
+        Ok(())
+    }
+
+    /// Removes all but the newest `keep` versions of each package, returning the removed files
+    pub fn prune(&self, keep: usize) -> Result<Vec<PathBuf>> {
+        let mut removed = Vec::new();
+        for dir in [self.root.join("rpms"), self.srpm_dir(), self.debug_dir()] {
+            let mut packages: BTreeMap<(String, String), Vec<(RpmName, PathBuf)>> = BTreeMap::new();
+            for rpm in rpm_files(&dir) {
+                let filename = rpm.file_name().unwrap_or_default().to_string_lossy().to_string();
+                if let Some(name) = RpmName::parse(&filename) {
+                    packages
+                        .entry((name.name.clone(), name.arch.clone()))
+                        .or_default()
+                        .push((name, rpm));
+                }
+            }
+
+            for (_, mut versions) in packages {
+                versions.sort_by(|(a, _), (b, _)| b.cmp_evr(a));
+                for (_, rpm) in versions.into_iter().skip(keep) {
+                    info!("Pruning {}", rpm.display());
+                    std::fs::remove_file(&rpm)?;
+                    removed.push(rpm);
+                }
+            }
+        }
+        Ok(removed)
+    }
+
+    /// Updates the repository metadata, pruning old versions first if configured.
+    ///
+    /// If `rebuild` is set, the metadata is regenerated from scratch.
+    /// If `sign` is set, the `repomd.xml` of each (sub)repository is signed.
+    pub async fn update(&self, rebuild: bool, sign: Option<&SignOpts>) -> Result<()> {
+        if let Some(keep) = self.keep {
+            self.prune(keep)?;
+        }
+
+        let srpm = self.srpm_dir();
+        let debug = self.debug_dir();
+        let repos = [
+            (self.root.clone(), vec!["*.src.rpm", "*-debuginfo-*", "*-debugsource-*"]),
+            (srpm, vec![]),
+            (debug, vec![]),
+        ];
+
+        for (dir, excludes) in repos {
+            if dir != self.root && !dir.exists() {
+                continue;
+            }
+            std::fs::create_dir_all(&dir)?;
+            if rebuild {
+                let repodata = dir.join("repodata");
+                if repodata.exists() {
+                    std::fs::remove_dir_all(repodata)?;
+                }
+            }
+
+            let mut cmd = Command::new("createrepo_c");
+            cmd.arg("--quiet");
+            if !rebuild {
+                cmd.arg("--update");
+            }
+            for exclude in excludes {
+                cmd.arg("--excludes").arg(exclude);
+            }
+            cmd.arg(&dir);
+            cmd.log().await?;
+
+            if let Some(sign) = sign {
+                sign.sign_repo(&dir).await?;
+            }
+        }
+
+        self.write_repo_file()?;
+        Ok(())
+    }
+
+    /// Reorganizes the repository and regenerates all metadata from scratch
+    pub async fn rebuild(&self, sign: Option<&SignOpts>) -> Result<()> {
+        self.reorganize()?;
+        self.update(true, sign).await
+    }
+
+    /// Writes `<name>.repo` into the repository root
+    pub fn write_repo_file(&self) -> Result<PathBuf> {
+        let baseurl = match &self.baseurl {
+            Some(url) => url.trim_end_matches('/').to_string(),
+            None => format!("file://{}", self.root.canonicalize()?.display()),
+        };
+        let name = &self.name;
+
+        let mut repo = format!("[{name}]\nname={name}\nbaseurl={baseurl}\nenabled=1\n");
+        for (suffix, dir) in [("source", "srpm"), ("debuginfo", "debug")] {
+            if self.root.join(dir).join("repodata").exists() {
+                repo += &format!(
+                    "\n[{name}-{suffix}]\nname={name} - {suffix}\nbaseurl={baseurl}/{dir}\nenabled=0\n"
+                );
+            }
+        }
+
+        let path = self.root.join(format!("{name}.repo"));
+        std::fs::write(&path, repo)?;
+        Ok(path)
+    }
+}
+
+/// Entrypoint for `anda repo`
+pub async fn repo_command(
+    target_dir: &Path,
+    config: Option<&anda_config::RepoConfig>,
+    command: RepoCommand,
+    sign: Option<SignOpts>,
+) -> Result<()> {
+    let repo = RpmRepo::from_config(target_dir.join("rpm"), config);
+    match command {
+        RepoCommand::Prune { keep } => {
+            let keep = keep
+                .or(repo.keep)
+                .ok_or_else(|| eyre!("No --keep specified, and no `keep` set in the manifest"))?;
+            for rpm in repo.prune(keep)? {
+                println!("Removed {}", rpm.display());
+            }
+            repo.update(false, sign.as_ref()).await?;
+        }
+        RepoCommand::Rebuild => {
+            repo.rebuild(sign.as_ref()).await?;
+            println!("Rebuilt repository in {}", repo.root().display());
+        }
+    }
+    Ok(())
+}
+
+/// RPM files directly inside `dir`
+fn rpm_files(dir: &Path) -> Vec<PathBuf> {
+    match std::fs::read_dir(dir) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| p.is_file() && p.extension().is_some_and(|e| e == "rpm"))
+            .collect(),
+        Err(_) => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod test_repo {
+    use super::*;
+
+    #[test]
+    fn test_rpmvercmp() {
+        assert_eq!(rpmvercmp("1.0", "1.0"), Ordering::Equal);
+        assert_eq!(rpmvercmp("1.0", "1.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.10", "1.9"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0a", "1.0"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(rpmvercmp("1.0^git1", "1.0"), Ordering::Greater);
+        assert_eq!(rpmvercmp("1.0^git1", "1.0.1"), Ordering::Less);
+        assert_eq!(rpmvercmp("2.0.01", "2.0.1"), Ordering::Equal);
+    }
+
+    #[test]
+    fn test_prune() {
+        let dir = tempfile::tempdir().unwrap();
+        let repo = RpmRepo::new(dir.path().to_path_buf());
+        std::fs::create_dir_all(dir.path().join("rpms")).unwrap();
+        for rpm in [
+            "umpkg-0.3.9-1.fc37.noarch.rpm",
+            "umpkg-0.3.10-1.fc37.noarch.rpm",
+            "umpkg-0.3.10-2.fc37.noarch.rpm",
+            "umpkg-debuginfo-0.3.10-2.fc37.x86_64.rpm",
+        ] {
+            std::fs::write(dir.path().join("rpms").join(rpm), "").unwrap();
+        }
+
+        repo.reorganize().unwrap();
+        assert!(dir.path().join("debug/umpkg-debuginfo-0.3.10-2.fc37.x86_64.rpm").exists());
+
+        let removed = repo.prune(2).unwrap();
+        assert_eq!(removed, vec![dir.path().join("rpms/umpkg-0.3.9-1.fc37.noarch.rpm")]);
+    }
+}
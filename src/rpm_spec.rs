@@ -7,6 +7,9 @@
 use clap::clap_derive::ValueEnum;
 use tempfile::TempDir;
 
+use crate::cli::MockIsolation;
+use crate::mock_log::{analyze_logs, preserve_logs};
+use crate::sign::SignOpts;
 use crate::util::CommandLog;
 use async_trait::async_trait;
 use color_eyre::{eyre::eyre, Report, Result};
@@ -14,7 +17,25 @@ use std::mem::take;
 use std::path::{Path, PathBuf};
 use std::{collections::BTreeMap, str::FromStr};
 use tokio::process::Command;
-use tracing::{debug, info};
+use tracing::{debug, info, warn};
+
+/// The Version of a spec, with `macros` defined and expanded by `rpmspec`
+pub async fn spec_version(spec: &Path, macros: &BTreeMap<String, String>) -> Result<String> {
+    let mut cmd = Command::new("rpmspec");
+    cmd.arg("-q").arg("--srpm").arg("--qf").arg("%{version}").arg(spec);
+    for (name, value) in macros {
+        cmd.arg("-D").arg(format!("{name} {value}"));
+    }
+    let output = cmd.output().await.map_err(|e| eyre!("Failed to run rpmspec: {e}"))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to parse {}: {}",
+            spec.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
 
 #[derive(Clone, Debug)]
 pub struct RPMOptions {
@@ -44,10 +65,84 @@ pub struct RPMOptions {
     pub scm_opts: Vec<String>,
     /// Plugin Options (mock)
     pub plugin_opts: Vec<String>,
+    /// Directory to keep the build logs in, split into `srpm/` and `rpm/`
+    /// Only used if backend is mock
+    pub log_dir: Option<PathBuf>,
+    /// Expected sha256 checksums of remote sources, keyed by file name
+    pub source_checksums: BTreeMap<String, String>,
+    /// Directory the sources are staged in for the build
+    pub staging_dir: PathBuf,
+    /// Do not download sources, use the cached ones
+    pub offline: bool,
+    /// Derive build metadata from the git commit instead of the current date
+    pub reproducible: bool,
+    /// Extra environment variables for the build
+    pub env: BTreeMap<String, String>,
+    /// Sign the RPMs and repository metadata after the build
+    pub sign: Option<SignOpts>,
+    /// Chroot options for Mock
+    pub mock: MockOpts,
+}
+
+/// Chroot options for Mock
+#[derive(Clone, Debug, Default)]
+pub struct MockOpts {
+    pub isolation: Option<MockIsolation>,
+    /// Container image to create the bootstrap chroot from
+    pub bootstrap_image: Option<String>,
+    /// Do not clean the chroot before building
+    pub no_clean: bool,
+    /// Keep the chroot after building
+    pub no_cleanup_after: bool,
+    /// Enable or disable the root cache plugin
+    pub root_cache: Option<bool>,
+    /// Enable or disable the package manager cache plugin
+    pub package_cache: Option<bool>,
+    /// Unique suffix for the chroot name
+    pub uniqueext: Option<String>,
+}
+
+impl MockOpts {
+    fn args(&self, cmd: &mut Command) {
+        if let Some(isolation) = self.isolation {
+            let isolation = match isolation {
+                MockIsolation::Nspawn => "nspawn",
+                MockIsolation::Simple => "simple",
+            };
+            cmd.arg(format!("--isolation={isolation}"));
+        }
+
+        if let Some(image) = &self.bootstrap_image {
+            cmd.arg("--use-bootstrap-image").arg("--bootstrap-image").arg(image);
+        }
+
+        if self.no_clean {
+            cmd.arg("--no-clean");
+        }
+
+        if self.no_cleanup_after {
+            cmd.arg("--no-cleanup-after");
+        }
+
+        for (plugin, enabled) in
+            [("root_cache", self.root_cache), ("yum_cache", self.package_cache)]
+        {
+            match enabled {
+                Some(true) => cmd.arg(format!("--enable-plugin={plugin}")),
+                Some(false) => cmd.arg(format!("--disable-plugin={plugin}")),
+                None => continue,
+            };
+        }
+
+        if let Some(ext) = &self.uniqueext {
+            cmd.arg(format!("--uniqueext={ext}"));
+        }
+    }
 }
 
 impl RPMOptions {
     pub fn new(mock_config: Option<String>, sources: PathBuf, resultdir: PathBuf) -> Self {
+        let staging_dir = resultdir.join("sources");
         Self {
             mock_config,
             with: Vec::new(),
@@ -61,6 +156,14 @@ impl RPMOptions {
             scm_enable: false,
             scm_opts: Vec::new(),
             plugin_opts: Vec::new(),
+            log_dir: None,
+            source_checksums: BTreeMap::new(),
+            staging_dir,
+            offline: false,
+            reproducible: false,
+            env: BTreeMap::new(),
+            sign: None,
+            mock: MockOpts::default(),
         }
     }
     pub fn add_extra_repo(&mut self, repo: String) {
@@ -127,32 +230,7 @@ impl RPMBuilder {
     /// WARN: this will consume `options`!
     pub async fn build(&self, spec: &Path, options: &mut RPMOptions) -> Result<Vec<PathBuf>> {
         if let RPMBuilder::Mock = self {
-            let mut mock = MockBackend::new(
-                take(&mut options.mock_config),
-                take(&mut options.sources),
-                take(&mut options.resultdir),
-            );
-            for extra_repo in options.extra_repos.iter_mut().flatten() {
-                mock.add_extra_repo(take(extra_repo));
-            }
-            for (k, v) in options.macros.iter() {
-                mock.def_macro(k, v);
-            }
-            for with_flags in options.with.iter_mut() {
-                mock.with_flags_mut().push(take(with_flags));
-            }
-            for without_flags in options.without.iter_mut() {
-                mock.without_flags_mut().push(take(without_flags));
-            }
-            for config_opt in options.config_opts.iter_mut() {
-                mock.add_config_opt(take(config_opt));
-            }
-            mock.no_mirror(options.no_mirror);
-            mock.enable_scm(options.scm_enable);
-            mock.extend_scm_opts(take(&mut options.scm_opts));
-            mock.plugin_opts(take(&mut options.plugin_opts));
-
-            mock.build(spec).await
+            MockBackend::from_options(options).build(spec).await
         } else {
             let mut rpmbuild =
                 RPMBuildBackend::new(take(&mut options.sources), take(&mut options.resultdir));
@@ -169,6 +247,8 @@ impl RPMBuilder {
                 rpmbuild.without_flags_mut().push(take(without_flags));
             }
 
+            rpmbuild.envs(take(&mut options.env));
+
             rpmbuild.build(spec).await
         }
     }
@@ -179,8 +259,13 @@ pub trait RPMSpecBackend {
     async fn build_srpm(&self, spec: &Path) -> Result<PathBuf>;
     async fn build_rpm(&self, spec: &Path) -> Result<Vec<PathBuf>>;
 
+    /// Builds the SRPM and then the RPMs from it.
+    /// The SRPM is returned first, followed by the binary RPMs.
     async fn build(&self, spec: &Path) -> Result<Vec<PathBuf>> {
-        self.build_rpm(&self.build_srpm(spec).await?).await
+        let srpm = self.build_srpm(spec).await?;
+        let mut rpms = self.build_rpm(&srpm).await?;
+        rpms.insert(0, srpm);
+        Ok(rpms)
     }
 }
 
@@ -254,6 +339,9 @@ pub struct MockBackend {
     scm_enable: bool,
     scm_opts: Vec<String>,
     plugin_opts: Vec<String>,
+    log_dir: Option<PathBuf>,
+    env: BTreeMap<String, String>,
+    opts: MockOpts,
 }
 
 impl RPMExtraOptions for MockBackend {
@@ -292,9 +380,46 @@ impl MockBackend {
             scm_enable: false,
             scm_opts: Vec::new(),
             plugin_opts: Vec::new(),
+            log_dir: None,
+            env: BTreeMap::new(),
+            opts: MockOpts::default(),
         }
     }
 
+    /// WARN: this will consume `options`!
+    pub fn from_options(options: &mut RPMOptions) -> Self {
+        let mut mock = MockBackend::new(
+            take(&mut options.mock_config),
+            take(&mut options.sources),
+            take(&mut options.resultdir),
+        );
+        for extra_repo in options.extra_repos.iter_mut().flatten() {
+            mock.add_extra_repo(take(extra_repo));
+        }
+        for (k, v) in options.macros.iter() {
+            mock.def_macro(k, v);
+        }
+        for with_flags in options.with.iter_mut() {
+            mock.with_flags_mut().push(take(with_flags));
+        }
+        for without_flags in options.without.iter_mut() {
+            mock.without_flags_mut().push(take(without_flags));
+        }
+        for config_opt in options.config_opts.iter_mut() {
+            mock.add_config_opt(take(config_opt));
+        }
+        mock.no_mirror(options.no_mirror);
+        mock.enable_scm(options.scm_enable);
+        mock.extend_scm_opts(take(&mut options.scm_opts));
+        mock.plugin_opts(take(&mut options.plugin_opts));
+        if let Some(log_dir) = take(&mut options.log_dir) {
+            mock.log_dir(log_dir);
+        }
+        mock.envs(take(&mut options.env));
+        mock.mock_opts(take(&mut options.mock));
+        mock
+    }
+
     pub fn extend_config_opts(&mut self, opts: Vec<String>) {
         self.config_opts.extend(opts);
     }
@@ -326,6 +451,50 @@ impl MockBackend {
         self.plugin_opts.extend(opts);
     }
 
+    /// Keep mock's logs in `log_dir` after the build
+    pub fn log_dir(&mut self, log_dir: PathBuf) {
+        self.log_dir = Some(log_dir);
+    }
+
+    /// Sets environment variables for the build.
+    /// They are passed into the chroot through `config_opts['environment']`.
+    pub fn envs(&mut self, env: BTreeMap<String, String>) {
+        self.env.extend(env);
+    }
+
+    /// Sets the chroot options
+    pub fn mock_opts(&mut self, opts: MockOpts) {
+        self.opts = opts;
+    }
+
+    /// Runs a mock command with `resultdir` as its result directory.
+    ///
+    /// The logs mock writes there are copied to `subdir` of the log directory,
+    /// and if the command fails, they are inspected to report which part of the build broke.
+    async fn run_logged(&self, cmd: &mut Command, resultdir: &Path, subdir: &str) -> Result<()> {
+        let status = cmd.log().await;
+
+        let mut logdir = resultdir.to_path_buf();
+        if let Some(log_dir) = &self.log_dir {
+            let dest = log_dir.join(subdir);
+            match preserve_logs(resultdir, &dest) {
+                Ok(()) => {
+                    info!("Mock logs saved to {}", dest.display());
+                    logdir = dest;
+                }
+                Err(e) => warn!("Could not save mock logs to {}: {e:?}", dest.display()),
+            }
+        }
+
+        if let Err(e) = status {
+            return Err(match analyze_logs(&logdir) {
+                Some(failure) => e.wrap_err(failure.to_string()),
+                None => e,
+            });
+        }
+        Ok(())
+    }
+
     pub fn mock(&self) -> Command {
         let mut cmd = Command::new("mock");
 
@@ -335,6 +504,8 @@ impl MockBackend {
 
         cmd.arg("--verbose");
 
+        self.opts.args(&mut cmd);
+
         for repo in self.extra_repos.iter() {
             cmd.arg("-a").arg(repo);
         }
@@ -359,6 +530,11 @@ impl MockBackend {
             cmd.arg("--config-opts").arg(opt);
         }
 
+        // mock does not pass its own environment into the chroot
+        for (name, value) in self.env.iter() {
+            cmd.arg("--config-opts").arg(format!("environment.{name}={value}"));
+        }
+
         if self.scm_enable {
             cmd.arg("--scm-enable");
         }
@@ -368,6 +544,42 @@ impl MockBackend {
         }
         cmd
     }
+
+    /// Sets up the chroot for `spec` with its build dependencies, copies the spec and
+    /// sources in, then drops into an interactive shell in it
+    pub async fn shell(&self, spec: &Path) -> Result<()> {
+        let mut cmd = self.mock();
+        cmd.arg("--init");
+        cmd.log().await?;
+
+        let mut cmd = self.mock();
+        cmd.arg("--installdeps").arg(spec);
+        cmd.log().await?;
+
+        let mut cmd = self.mock();
+        cmd.arg("--copyin").arg(spec).arg("/builddir/build/SPECS/");
+        cmd.log().await?;
+
+        let mut sources = Vec::new();
+        for entry in std::fs::read_dir(&self.sources)? {
+            let path = entry?.path();
+            if path.is_file() {
+                sources.push(path);
+            }
+        }
+        if !sources.is_empty() {
+            let mut cmd = self.mock();
+            cmd.arg("--copyin").args(&sources).arg("/builddir/build/SOURCES/");
+            cmd.log().await?;
+        }
+
+        info!("Entering the build chroot, the spec is in /builddir/build/SPECS");
+        let status = self.mock().arg("--shell").arg("--enable-network").status().await?;
+        if !status.success() {
+            return Err(eyre!("mock --shell exited with {status}"));
+        }
+        Ok(())
+    }
 }
 
 #[async_trait]
@@ -387,7 +599,7 @@ impl RPMSpecBackend for MockBackend {
 
         // cmd.status()?;
 
-        cmd.log().await?;
+        self.run_logged(&mut cmd, tmp.path(), "srpm").await?;
 
         // find srpm in resultdir using walkdir
 
@@ -417,7 +629,7 @@ impl RPMSpecBackend for MockBackend {
         let tmp = tempfile::Builder::new().prefix("anda-rpm").tempdir()?;
         cmd.arg("--rebuild").arg(spec).arg("--enable-network").arg("--resultdir").arg(tmp.path());
 
-        cmd.log().await?;
+        self.run_logged(&mut cmd, tmp.path(), "rpm").await?;
 
         // find rpms in resultdir using walkdir
 
@@ -459,6 +671,7 @@ pub struct RPMBuildBackend {
     with: Vec<String>,
     without: Vec<String>,
     macros: BTreeMap<String, String>,
+    env: BTreeMap<String, String>,
 }
 
 impl RPMExtraOptions for RPMBuildBackend {
@@ -484,11 +697,24 @@ impl RPMExtraOptions for RPMBuildBackend {
 
 impl RPMBuildBackend {
     pub fn new(sources: PathBuf, resultdir: PathBuf) -> Self {
-        Self { sources, resultdir, with: Vec::new(), without: Vec::new(), macros: BTreeMap::new() }
+        Self {
+            sources,
+            resultdir,
+            with: Vec::new(),
+            without: Vec::new(),
+            macros: BTreeMap::new(),
+            env: BTreeMap::new(),
+        }
+    }
+
+    /// Sets environment variables for rpmbuild
+    pub fn envs(&mut self, env: BTreeMap<String, String>) {
+        self.env.extend(env);
     }
 
     pub fn rpmbuild(&self) -> Command {
         let mut cmd = Command::new("rpmbuild");
+        cmd.envs(&self.env);
 
         for with in self.with.iter() {
             cmd.arg("--with").arg(with);
@@ -607,8 +833,8 @@ impl RPMSpecBackend for RPMBuildBackend {
                 let srpm_dir = self.resultdir.join("rpm/srpm");
                 std::fs::create_dir_all(&srpm_dir)?;
                 let dest = srpm_dir.join(entry.file_name());
-                std::fs::copy(entry.path(), dest)?;
-                //rpms.push(srpm_dir.join(entry.file_name()));
+                std::fs::copy(entry.path(), &dest)?;
+                rpms.insert(0, dest);
             } else if entry.file_name().to_string_lossy().ends_with(".rpm") {
                 //rpms.push(entry.path().to_path_buf());
                 // eprintln!("found rpm: {:?}", rpms);
@@ -625,3 +851,50 @@ impl RPMSpecBackend for RPMBuildBackend {
         Ok(rpms)
     }
 }
+
+#[cfg(test)]
+mod test_rpm_spec {
+    use super::*;
+
+    #[test]
+    fn test_mock_opts() {
+        let mut mock = MockBackend::new(Some("fedora-37-x86_64".into()), ".".into(), ".".into());
+        mock.mock_opts(MockOpts {
+            isolation: Some(MockIsolation::Simple),
+            no_cleanup_after: true,
+            root_cache: Some(false),
+            uniqueext: Some("job1".into()),
+            ..Default::default()
+        });
+
+        let cmd = mock.mock();
+        let args = cmd.as_std().get_args().map(|a| a.to_string_lossy()).collect::<Vec<_>>();
+        assert_eq!(
+            args[..7],
+            [
+                "-r",
+                "fedora-37-x86_64",
+                "--verbose",
+                "--isolation=simple",
+                "--no-cleanup-after",
+                "--disable-plugin=root_cache",
+                "--uniqueext=job1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_source_date_epoch() {
+        let mut opts = RPMOptions::new(None, ".".into(), ".".into());
+        opts.reproducible = true;
+        crate::builder::define_macros(&mut opts, &Default::default()).unwrap();
+        let epoch = opts.env["SOURCE_DATE_EPOCH"].clone();
+
+        let cmd = MockBackend::from_options(&mut opts).mock();
+        let args = cmd.as_std().get_args().map(|a| a.to_string_lossy()).collect::<Vec<_>>();
+        let env = format!("environment.SOURCE_DATE_EPOCH={epoch}");
+        assert!(args.windows(2).any(|w| w[0] == "--config-opts" && w[1] == env));
+        // rpm still falls back to the changelog when SOURCE_DATE_EPOCH is unset
+        assert!(!args.iter().any(|a| a.starts_with("source_date_epoch_from_changelog")));
+    }
+}
@@ -0,0 +1,107 @@
+//! GPG signing for RPMs and repository metadata
+//!
+//! Everything is done with the local gpg keyring (or `homedir`, if set),
+//! so no external signing service is needed.
+
+use crate::util::CommandLog;
+use color_eyre::{eyre::eyre, Result};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::info;
+
+#[derive(Clone, Debug)]
+pub struct SignOpts {
+    /// GPG key ID (or user ID) to sign with
+    pub key: String,
+    /// GPG home directory, defaults to the user's keyring
+    pub homedir: Option<PathBuf>,
+}
+
+impl SignOpts {
+    pub fn new(key: String, homedir: Option<PathBuf>) -> Self {
+        Self { key, homedir }
+    }
+
+    /// Signs RPMs in place with `rpmsign --addsign`
+    pub async fn sign_rpms(&self, rpms: &[PathBuf]) -> Result<()> {
+        if rpms.is_empty() {
+            return Ok(());
+        }
+        info!("Signing {} RPM(s) with {}", rpms.len(), self.key);
+
+        let mut cmd = Command::new("rpmsign");
+        cmd.arg("--addsign").arg("--define").arg(format!("_gpg_name {}", self.key));
+        if let Some(homedir) = &self.homedir {
+            cmd.arg("--define").arg(format!("_gpg_path {}", homedir.display()));
+        }
+        cmd.args(rpms);
+        cmd.log().await
+    }
+
+    /// Creates a detached, armored signature for `repodata/repomd.xml` in `repo`
+    pub async fn sign_repo(&self, repo: &Path) -> Result<PathBuf> {
+        let repomd = repo.join("repodata/repomd.xml");
+        if !repomd.exists() {
+            return Err(eyre!("No repository metadata in {}", repo.display()));
+        }
+        info!("Signing {} with {}", repomd.display(), self.key);
+
+        let mut cmd = self.gpg();
+        cmd.arg("--local-user").arg(&self.key).arg("--detach-sign").arg("--armor").arg(&repomd);
+        cmd.log().await?;
+
+        Ok(repo.join("repodata/repomd.xml.asc"))
+    }
+
+    fn gpg(&self) -> Command {
+        let mut cmd = Command::new("gpg");
+        cmd.arg("--batch").arg("--yes");
+        if let Some(homedir) = &self.homedir {
+            cmd.arg("--homedir").arg(homedir);
+        }
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod test_sign {
+    use super::*;
+
+    #[tokio::test]
+    #[ignore = "needs gpg on the host"]
+    async fn test_sign_repo() {
+        // throwaway keyring
+        let home = tempfile::tempdir().unwrap();
+        let sign = SignOpts::new("anda-test@example.com".to_string(), Some(home.path().into()));
+        let status = sign
+            .gpg()
+            .args(["--pinentry-mode", "loopback", "--passphrase", ""])
+            .args(["--quick-gen-key", &sign.key, "default", "default"])
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+
+        let repo = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(repo.path().join("repodata")).unwrap();
+        std::fs::write(repo.path().join("repodata/repomd.xml"), "<repomd/>").unwrap();
+
+        let sig = sign.sign_repo(repo.path()).await.unwrap();
+        let status = sign
+            .gpg()
+            .arg("--verify")
+            .arg(&sig)
+            .arg(repo.path().join("repodata/repomd.xml"))
+            .status()
+            .await
+            .unwrap();
+        assert!(status.success());
+
+        let _ = Command::new("gpgconf")
+            .arg("--homedir")
+            .arg(home.path())
+            .args(["--kill", "gpg-agent"])
+            .status()
+            .await;
+    }
+}
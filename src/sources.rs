@@ -0,0 +1,293 @@
+//! RPM source fetching
+//!
+//! Instead of letting rpmbuild/mock download `SourceN`/`PatchN` URLs on every build,
+//! we resolve them from the spec ourselves and keep them in a content-addressed cache
+//! shared by all projects, so repeat builds work offline.
+//!
+//! The cache lives in `~/.cache/anda/sources`:
+//! - `sha256/<digest>`: the source files, named by their SHA-256 digest
+//! - `urls/<digest of url>`: the digest of the file last downloaded from that URL
+//!
+//! The sources for a build are staged in `<target_dir>/sources/<project>`,
+//! so the project's own sources directory is never written to.
+
+use crate::rpm_spec::RPMOptions;
+use color_eyre::{eyre::eyre, Result};
+use lazy_static::lazy_static;
+use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::{
+    collections::BTreeMap,
+    fs::File,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::process::Command;
+use tracing::{debug, info, warn};
+
+lazy_static! {
+    static ref SOURCE_REGEX: Regex =
+        Regex::new(r"(?mi)^\s*(?:Source|Patch)\d*\s*:\s*(\S+)\s*$").unwrap();
+}
+
+/// Content-addressed cache for downloaded sources
+pub struct SourceCache {
+    root: PathBuf,
+}
+
+impl SourceCache {
+    pub fn new(root: PathBuf) -> Self {
+        Self { root }
+    }
+
+    /// The default cache in `~/.cache/anda/sources`
+    pub fn user() -> Result<Self> {
+        let cache = dirs_next::cache_dir().ok_or_else(|| eyre!("Cannot find cache directory"))?;
+        Ok(Self::new(cache.join("anda/sources")))
+    }
+
+    fn blob(&self, digest: &str) -> PathBuf {
+        self.root.join("sha256").join(digest)
+    }
+
+    fn url_index(&self, url: &str) -> PathBuf {
+        self.root.join("urls").join(format!("{:x}", Sha256::digest(url.as_bytes())))
+    }
+
+    /// Looks up `url` in the cache without downloading anything
+    pub fn get(&self, url: &str, checksum: Option<&str>) -> Option<PathBuf> {
+        let sum = match checksum {
+            Some(sum) => sum.to_string(),
+            None => std::fs::read_to_string(self.url_index(url)).ok()?.trim().to_string(),
+        };
+        let blob = self.blob(&sum);
+        if blob.exists() {
+            debug!("{url}: found {sum} in cache");
+            return Some(blob);
+        }
+        None
+    }
+
+    /// Looks up `url` in the cache, downloading it if needed.
+    ///
+    /// If `checksum` is set, the returned file is guaranteed to match it.
+    pub fn fetch(&self, url: &str, checksum: Option<&str>) -> Result<PathBuf> {
+        if let Some(blob) = self.get(url, checksum) {
+            return Ok(blob);
+        }
+
+        info!("Downloading {url}");
+        std::fs::create_dir_all(self.root.join("sha256"))?;
+        let mut tmp = tempfile::NamedTempFile::new_in(&self.root)?;
+        let resp = ureq::get(url)
+            .set("User-Agent", concat!("anda/", env!("CARGO_PKG_VERSION")))
+            .call()
+            .map_err(|e| eyre!("Failed to download {url}: {e}"))?;
+        io::copy(&mut resp.into_reader(), &mut tmp)?;
+
+        let digest = sha256sum(tmp.path())?;
+        if let Some(sum) = checksum {
+            if sum != digest {
+                return Err(eyre!("Checksum mismatch for {url}: expected {sum}, got {digest}"));
+            }
+        }
+
+        let blob = self.blob(&digest);
+        tmp.persist(&blob)?;
+        let index = self.url_index(url);
+        std::fs::create_dir_all(index.parent().unwrap())?;
+        std::fs::write(index, &digest)?;
+        Ok(blob)
+    }
+}
+
+/// SHA-256 digest of a file, as lowercase hex
+pub fn sha256sum(path: &Path) -> Result<String> {
+    let mut file = File::open(path)?;
+    let mut hasher = Sha256::new();
+    let mut buf = [0; 8192];
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Parses a checksum declared in anda.hcl, e.g. `sha256:<digest>`
+fn parse_checksum(sum: &str) -> Result<String> {
+    let digest = sum.strip_prefix("sha256:").unwrap_or(sum);
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(eyre!("Invalid checksum `{sum}`, only sha256 is supported"));
+    }
+    Ok(digest.to_lowercase())
+}
+
+/// Returns the `Source`/`Patch` entries of a spec, with macros expanded by `rpmspec`
+pub async fn spec_sources(spec: &Path, macros: &BTreeMap<String, String>) -> Result<Vec<String>> {
+    let mut cmd = Command::new("rpmspec");
+    cmd.arg("-P").arg(spec);
+    for (name, value) in macros {
+        cmd.arg("-D").arg(format!("{name} {value}"));
+    }
+    let output = cmd.output().await.map_err(|e| eyre!("Failed to run rpmspec: {e}"))?;
+    if !output.status.success() {
+        return Err(eyre!(
+            "Failed to parse {}: {}",
+            spec.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(parse_sources(&String::from_utf8_lossy(&output.stdout)))
+}
+
+fn parse_sources(spec: &str) -> Vec<String> {
+    SOURCE_REGEX.captures_iter(spec).map(|cap| cap[1].to_string()).collect()
+}
+
+/// The file name rpmbuild expects for a source URL.
+/// A `#/name` fragment overrides the name in the URL.
+pub fn source_filename(url: &str) -> String {
+    if let Some((_, name)) = url.rsplit_once("#/") {
+        return name.to_string();
+    }
+    let url = url.split(['?', '#']).next().unwrap_or(url);
+    url.trim_end_matches('/').rsplit('/').next().unwrap_or(url).to_string()
+}
+
+/// Stages all sources of `spec` in `opts.staging_dir`.
+///
+/// Remote sources come from the cache, verified against `opts.source_checksums`;
+/// local ones are copied from `opts.sources`.
+pub async fn fetch_sources(spec: &Path, opts: &RPMOptions) -> Result<Vec<PathBuf>> {
+    let entries = spec_sources(spec, &opts.macros).await?;
+    let cache = Arc::new(SourceCache::user()?);
+    let mut staged = Vec::new();
+
+    std::fs::create_dir_all(&opts.staging_dir)?;
+    for entry in entries {
+        let name = source_filename(&entry);
+        let src = if entry.contains("://") {
+            let checksum =
+                opts.source_checksums.get(&name).map(|s| parse_checksum(s)).transpose()?;
+            fetch_remote(&cache, &entry, checksum, opts).await?
+        } else {
+            let local = opts.sources.join(&name);
+            if !local.exists() {
+                warn!("Source {name} not found in {}", opts.sources.display());
+                continue;
+            }
+            local
+        };
+
+        let dest = opts.staging_dir.join(&name);
+        if dest.exists() && sha256sum(&dest)? == sha256sum(&src)? {
+            debug!("{} is up to date", dest.display());
+        } else {
+            debug!("Staging {} as {}", src.display(), dest.display());
+            std::fs::copy(&src, &dest)?;
+        }
+        staged.push(dest);
+    }
+
+    Ok(staged)
+}
+
+/// Gets a remote source from the cache.
+///
+/// In offline mode nothing is downloaded, and a copy in the project's sources directory
+/// is used if the cache doesn't have it.
+async fn fetch_remote(
+    cache: &Arc<SourceCache>,
+    url: &str,
+    checksum: Option<String>,
+    opts: &RPMOptions,
+) -> Result<PathBuf> {
+    if !opts.offline {
+        let (cache, u) = (cache.clone(), url.to_string());
+        return tokio::task::spawn_blocking(move || cache.fetch(&u, checksum.as_deref())).await?;
+    }
+
+    if let Some(blob) = cache.get(url, checksum.as_deref()) {
+        return Ok(blob);
+    }
+
+    let local = opts.sources.join(source_filename(url));
+    if !local.exists() {
+        return Err(eyre!("{url} is not in the source cache, run `anda fetch` first"));
+    }
+    if let Some(sum) = checksum {
+        let digest = sha256sum(&local)?;
+        if sum != digest {
+            return Err(eyre!(
+                "Checksum mismatch for {}: expected {sum}, got {digest}",
+                local.display()
+            ));
+        }
+    }
+    Ok(local)
+}
+
+#[cfg(test)]
+mod test_sources {
+    use super::*;
+
+    #[test]
+    fn test_parse_sources() {
+        let spec = "Name: umpkg\n\
+                    Source0:        https://github.com/Ultramarine-Linux/umpkg/archive/refs/tags/0.3.63.tar.gz\n\
+                    Source1: umpkg.conf\n\
+                    Patch0: https://example.com/fix.patch#/0001-fix.patch\n";
+
+        let sources = parse_sources(spec);
+        assert_eq!(sources.len(), 3);
+        assert_eq!(source_filename(&sources[0]), "0.3.63.tar.gz");
+        assert_eq!(source_filename(&sources[1]), "umpkg.conf");
+        assert_eq!(source_filename(&sources[2]), "0001-fix.patch");
+        assert_eq!(source_filename("https://example.com/foo.tar.gz?raw=true"), "foo.tar.gz");
+    }
+
+    #[test]
+    fn test_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = SourceCache::new(dir.path().to_path_buf());
+        let file = dir.path().join("hello");
+        std::fs::write(&file, "hello").unwrap();
+
+        let digest = sha256sum(&file).unwrap();
+        assert_eq!(digest, "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+        assert_eq!(parse_checksum(&format!("sha256:{digest}")).unwrap(), digest);
+        assert!(parse_checksum("md5:abc").is_err());
+
+        // anything already in the cache is used without touching the network
+        std::fs::create_dir_all(dir.path().join("sha256")).unwrap();
+        std::fs::copy(&file, cache.blob(&digest)).unwrap();
+        let blob = cache.fetch("https://invalid.invalid/hello", Some(&digest)).unwrap();
+        assert_eq!(std::fs::read_to_string(blob).unwrap(), "hello");
+    }
+
+    #[tokio::test]
+    async fn test_offline() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = Arc::new(SourceCache::new(dir.path().join("cache")));
+        let mut opts = RPMOptions::new(None, dir.path().join("sources"), dir.path().join("target"));
+        opts.offline = true;
+
+        // nothing is downloaded in offline mode
+        let url = "https://invalid.invalid/hello.tar.gz";
+        assert!(fetch_remote(&cache, url, None, &opts).await.is_err());
+
+        // but a copy in the sources directory is fine, as long as it matches the checksum
+        std::fs::create_dir_all(&opts.sources).unwrap();
+        std::fs::write(opts.sources.join("hello.tar.gz"), "hello").unwrap();
+        let src = fetch_remote(&cache, url, None, &opts).await.unwrap();
+        assert_eq!(src, opts.sources.join("hello.tar.gz"));
+
+        let digest = "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824";
+        assert!(fetch_remote(&cache, url, Some(digest.to_string()), &opts).await.is_ok());
+        assert!(fetch_remote(&cache, url, Some("0".repeat(64)), &opts).await.is_err());
+    }
+}